@@ -2,39 +2,41 @@ extern crate chashmap;
 extern crate rand;
 extern crate socket2;
 
-use std::io::Read;
 use std::net::Ipv4Addr;
 use std::net::*;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
 
 use serde::{Deserialize, Serialize};
 use socket2::*;
 
-use self::chashmap::{ReadGuard, WriteGuard};
+use self::chashmap::ReadGuard;
 use self::rand::prelude::ThreadRng;
-use self::rand::seq::{IteratorRandom, SliceRandom};
-use crate::common::{Address, BroadcastMessage, MessageType, NodeMeta};
-use crate::events::Event::{JoinIn, JoinOut, LeftIn};
-use crate::events::{Event, EventListener, EventLoop};
+use self::rand::seq::SliceRandom;
+use crate::common::{Address, BroadcastMessage, GossipMessage, MessageType, NodeMeta};
+use crate::events::Event::{JoinIn, LeftIn};
+use crate::events::{
+    Event, EventListener, EventLoop, ListenerQueue, Listeners, Subscription, SubscriptionStream,
+};
+use crate::limits::Limits;
 use crate::membership::MembershipService;
 use crate::message::MessagingService;
 use crate::serialize;
 
-use crate::config::{BroadcastConfig, DiscoveryConfig};
-use core::borrow::BorrowMut;
+use crate::config::BroadcastConfig;
+use crate::serialize::CodecKind;
+use crate::trace::{self, TraceContext};
 use crossbeam_channel::{Receiver, Sender};
-use std::cell::RefCell;
-use std::collections::btree_set::BTreeSet;
+use std::collections::HashMap;
 use std::time::Duration;
 use uuid::Uuid;
 
-const MULTICAST_INPUT_BUFF_SIZE: usize = 256;
+const MULTICAST_INPUT_BUFF_SIZE: usize = 1024;
 
 /**Listens on multicast messages. Sends messages via multicast*/
 pub struct BroadcastService {
     multicast_address: Address,
+    codec: CodecKind,
     sender_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
     handler_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
     gossip_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
@@ -43,32 +45,35 @@ pub struct BroadcastService {
     receiver_channel: Receiver<DiscoveryMessage>,
     //gossip
     gossip: Arc<GossipProtocol>,
-    event_loop: Arc<RwLock<EventLoop>>,
+    event_loop: Arc<EventLoop>,
 }
 
 impl BroadcastService {
-    pub fn new(
-        local_node_meta: NodeMeta,
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        _local_node_meta: NodeMeta,
         config: BroadcastConfig,
         multicast_address: Address,
+        codec: CodecKind,
         membership_service: Arc<RwLock<MembershipService>>,
         messaging_service: Arc<RwLock<MessagingService>>,
-        event_loop: Arc<RwLock<EventLoop>>,
+        limits: Arc<Limits>,
+        event_loop: Arc<EventLoop>,
     ) -> BroadcastService {
         let (s, r): (Sender<DiscoveryMessage>, Receiver<DiscoveryMessage>) =
             crossbeam_channel::unbounded();
 
-        let l = event_loop.clone();
-
         let gossip = Arc::new(GossipProtocol::new(
             config,
             membership_service,
             messaging_service,
+            limits,
             event_loop.clone(),
         ));
 
         BroadcastService {
             multicast_address,
+            codec,
             sender_thread: Arc::new(Mutex::new(Option::None)),
             handler_thread: Arc::new(Mutex::new(Option::None)),
             gossip_thread: Arc::new(Mutex::new(Option::None)),
@@ -95,6 +100,7 @@ impl BroadcastService {
         //set thread handler to service. Service is the thread owner
         self.sender_thread.lock().unwrap().replace(sender_thread);
         self.handler_thread.lock().unwrap().replace(handler_thread);
+        self.gossip_thread.lock().unwrap().replace(gossip_thread);
         println!("[BroadcastService]: Started");
 
         Ok(())
@@ -103,7 +109,9 @@ impl BroadcastService {
     fn build_socket_send(&self, multi_sock_addr: &SockAddr) -> Result<Socket, &str> {
         let socket =
             socket2::Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp())).unwrap();
-        socket.connect(multi_sock_addr);
+        socket
+            .connect(multi_sock_addr)
+            .map_err(|_| "Failed to connect the multicast socket")?;
 
         Ok(socket)
     }
@@ -111,25 +119,30 @@ impl BroadcastService {
     fn build_socket_receive(&self, multi_addr: &Ipv4Addr, multi_port: u16) -> Result<Socket, &str> {
         let socket =
             socket2::Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp())).unwrap();
-        socket.set_reuse_port(true);
+        socket
+            .set_reuse_port(true)
+            .map_err(|_| "Failed to reuse the multicast port")?;
         socket
             .bind(&SockAddr::from(SocketAddrV4::new(
                 Ipv4Addr::UNSPECIFIED,
                 multi_port,
             )))
             .unwrap();
-        socket.join_multicast_v4(multi_addr, &Ipv4Addr::UNSPECIFIED);
+        socket
+            .join_multicast_v4(multi_addr, &Ipv4Addr::UNSPECIFIED)
+            .map_err(|_| "Failed to join the multicast group")?;
 
         Ok(socket)
     }
 
     fn start_sending(&self, socket: Socket) -> Result<std::thread::JoinHandle<()>, &str> {
         let receiver_channel_ = self.receiver_channel.clone();
+        let codec = self.codec;
 
         let thread = std::thread::spawn(move || {
             println!("[BroadcastService]: Started sending multicast messages");
             for msg in receiver_channel_.iter() {
-                let msg_bytes = serialize::to_bytes(&msg).unwrap();
+                let msg_bytes = serialize::to_frame(codec, &msg).unwrap();
 
                 match socket.send(msg_bytes.as_slice()) {
                     Ok(_) => {
@@ -145,18 +158,24 @@ impl BroadcastService {
 
     fn start_listening(&self, socket: Socket) -> Result<std::thread::JoinHandle<()>, &str> {
         let e_loop_ = self.event_loop.clone();
+        let local_codec = self.codec;
 
         let thread = std::thread::spawn(move || loop {
             let mut buff = [0u8; MULTICAST_INPUT_BUFF_SIZE];
 
             match socket.recv_from(&mut buff) {
-                Ok((size, ref sockaddr)) if size > 0 => match serialize::from_bytes(&buff) {
-                    Ok(msg) => {
-                        let event = self::BroadcastService::build_discovery_event(&msg, &sockaddr);
-                        e_loop_.read().unwrap().post_event(event);
+                Ok((size, _)) if size > 0 => {
+                    if let Ok((msg, codec)) = serialize::from_frame(&buff[..size]) {
+                        if codec != local_codec {
+                            eprintln!(
+                                "[BroadcastService]: Discovery message encoded with {:?}, local codec is {:?}",
+                                codec, local_codec
+                            );
+                        }
+                        let event = self::BroadcastService::build_discovery_event(&msg);
+                        e_loop_.post_event(event);
                     }
-                    Err(_) => {}
-                },
+                }
                 Err(_) => eprintln!("[BroadcastService]: Read message via multicast: ERR"),
                 _ => {}
             }
@@ -175,13 +194,12 @@ impl BroadcastService {
         Ok(thread)
     }
 
-    fn build_discovery_event(msg: &DiscoveryMessage, sockaddr: &SockAddr) -> Event {
-        let ip = sockaddr.as_inet().map(|i| i.ip().clone()).unwrap();
-        let port = sockaddr.as_inet().map(|i| i.port()).unwrap();
-
+    fn build_discovery_event(msg: &DiscoveryMessage) -> Event {
         match msg.r#type {
             DiscoveryMessageType::Joined => JoinIn {
                 node_meta: msg.node_meta.clone(),
+                incarnation: msg.incarnation,
+                tags: msg.tags.clone(),
             },
             DiscoveryMessageType::Left => LeftIn {
                 node_meta: msg.node_meta.clone(),
@@ -189,42 +207,48 @@ impl BroadcastService {
         }
     }
 
-    fn send_join_message(&self, node: NodeMeta) {
+    fn send_join_message(&self, node: NodeMeta, incarnation: u64, tags: HashMap<String, String>) {
         let msg = DiscoveryMessage {
             r#type: DiscoveryMessageType::Joined,
             node_meta: node,
+            incarnation,
+            tags,
         };
 
-        self.sender_channel.send(msg);
+        if let Err(_) = self.sender_channel.send(msg) {
+            eprintln!("[BroadcastService]: Error while queueing join message!");
+        }
     }
 
-    fn send_leave_message(&self, node: NodeMeta) {
-        let msg = DiscoveryMessage {
-            r#type: DiscoveryMessageType::Left,
-            node_meta: node,
-        };
+    pub fn add_broadcast_listener<F>(&self, f: F) -> Subscription
+    where
+        F: Fn(Arc<BroadcastMessage>) + 'static + Send + Sync,
+    {
+        self.gossip.add_listener(f)
+    }
 
-        self.sender_channel.send(msg);
+    pub fn subscribe_broadcasts(&self) -> Receiver<Arc<BroadcastMessage>> {
+        self.gossip.subscribe()
     }
 
-    pub fn add_broadcast_listener<F>(&self, f: F) -> Result<(), Box<()>>
-    where
-        F: Fn(Arc<BroadcastMessage>) -> () + 'static + Send + Sync,
-    {
-        match self.gossip.add_listener(f) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(Box::new(())),
-        }
+    pub fn subscribe_broadcasts_stream(&self) -> SubscriptionStream<Arc<BroadcastMessage>> {
+        self.gossip.subscribe_stream()
     }
 }
 
 impl EventListener for BroadcastService {
     fn on_event(&self, event: Event) {
         match event {
-            Event::JoinOut { node_meta } => self.send_join_message(node_meta),
-            Event::LeftOut { node_meta } => self.send_leave_message(node_meta),
+            Event::JoinOut {
+                node_meta,
+                incarnation,
+                tags,
+            } => self.send_join_message(node_meta, incarnation, tags),
             Event::BroadcastIn { payload } => self.gossip.handle_received_broadcast(payload),
-            Event::BroadcastOut { payload } => self.gossip.send_new_broadcast(payload),
+            Event::BroadcastOut {
+                payload,
+                traceparent,
+            } => self.gossip.send_new_broadcast(payload, traceparent),
             _ => {}
         }
     }
@@ -238,23 +262,26 @@ enum DiscoveryMessageType {
 }
 
 /**Message that multicasts*/
-#[derive(Serialize, Deserialize, PartialEq, Debug, Hash)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct DiscoveryMessage {
     r#type: DiscoveryMessageType,
     node_meta: NodeMeta,
+    incarnation: u64,
+    tags: HashMap<String, String>,
 }
 
 /**Gossip protocol implementation and process*/
 struct GossipProtocol {
     config: BroadcastConfig,
-    listeners: RwLock<Vec<Box<Fn(Arc<BroadcastMessage>) -> () + 'static + Send + Sync>>>,
+    listeners: Listeners<Arc<ListenerQueue<Arc<BroadcastMessage>>>>,
     send_buffer: chashmap::CHashMap<Uuid, Arc<RwLock<BufferedBroadcast>>>,
     keep_buffer: chashmap::CHashMap<Uuid, Arc<RwLock<BufferedBroadcast>>>,
     send_keys: RwLock<Vec<Uuid>>,
     keep_keys: RwLock<Vec<Uuid>>,
     membership_service: Arc<RwLock<MembershipService>>,
     messaging_service: Arc<RwLock<MessagingService>>,
-    event_loop: Arc<RwLock<EventLoop>>,
+    limits: Arc<Limits>,
+    event_loop: Arc<EventLoop>,
 }
 
 impl GossipProtocol {
@@ -262,41 +289,59 @@ impl GossipProtocol {
         config: BroadcastConfig,
         membership_service: Arc<RwLock<MembershipService>>,
         messaging_service: Arc<RwLock<MessagingService>>,
-        event_loop: Arc<RwLock<EventLoop>>,
+        limits: Arc<Limits>,
+        event_loop: Arc<EventLoop>,
     ) -> GossipProtocol {
         GossipProtocol {
             config,
-            listeners: RwLock::new(Vec::new()),
+            listeners: Listeners::new(),
             send_buffer: chashmap::CHashMap::new(),
             keep_buffer: chashmap::CHashMap::new(),
             send_keys: RwLock::new(Vec::new()),
             keep_keys: RwLock::new(Vec::new()),
             membership_service,
             messaging_service,
+            limits,
             event_loop,
         }
     }
 
     fn start(&self) {
-        let mut rng = &mut rand::thread_rng();
+        let rng = &mut rand::thread_rng();
 
         loop {
-            let buffered_broadcast = self.choose_message_to_broadcast(&mut rng);
+            let buffered_broadcast = self.choose_message_to_broadcast(rng);
 
             let peer_count = self.membership_service.read().unwrap().get_member_count();
 
             if peer_count != 0 {
-                if let Some(mut msg) = buffered_broadcast {
-                    {
+                if let Some(msg) = buffered_broadcast {
+                    let sent = {
                         let payload = &msg.read().unwrap().payload;
-                        let bytes_to_broadcast = serialize::to_bytes(payload).unwrap();
+                        // gossip of the broadcast continues the trace it was started in
+                        let context = payload
+                            .traceparent
+                            .as_ref()
+                            .and_then(|value| TraceContext::from_traceparent(value))
+                            .map(|context| context.child());
+                        let _context = trace::enter(context);
+                        let span = tracing::debug_span!(
+                            "hover.gossip",
+                            broadcast = %payload.id,
+                            traceparent = ?payload.traceparent
+                        );
+                        let _span = span.enter();
+
                         let peers = self.choose_peers_to_broadcast(rng);
 
-                        self.do_broadcast(bytes_to_broadcast, peers);
-                    }
+                        self.do_broadcast(payload, peers)
+                    };
 
-                    // decrease ttl of the current message
-                    msg.write().unwrap().rounds -= 1_i32;
+                    // decrease ttl of the current message. A round put off by the
+                    // bandwidth cap does not count
+                    if sent {
+                        msg.write().unwrap().rounds -= 1_i32;
+                    }
                 }
 
                 self.move_to_keep_buffer();
@@ -309,10 +354,11 @@ impl GossipProtocol {
     }
 
     //put message into buffer
-    fn send_new_broadcast(&self, payload: Vec<u8>) {
+    fn send_new_broadcast(&self, payload: Vec<u8>, traceparent: Option<String>) {
         let broadcast_payload = BroadcastMessage {
             id: uuid::Uuid::new_v4(),
             payload,
+            traceparent,
         };
 
         self.add_to_send_buffer(broadcast_payload);
@@ -328,7 +374,7 @@ impl GossipProtocol {
     }
 
     fn add_to_send_buffer(&self, payload: BroadcastMessage) {
-        let key = payload.id.clone();
+        let key = payload.id;
         let nodes = self.membership_service.read().unwrap().get_member_count() as isize as f32;
 
         let buffered_message = BufferedBroadcast {
@@ -338,7 +384,7 @@ impl GossipProtocol {
         };
 
         self.send_buffer
-            .insert_new(key.clone(), Arc::new(RwLock::new(buffered_message)));
+            .insert_new(key, Arc::new(RwLock::new(buffered_message)));
         if self.send_buffer.get(&key).is_some() {
             self.send_keys.write().unwrap().push(key)
         }
@@ -357,7 +403,7 @@ impl GossipProtocol {
     fn choose_message_to_broadcast(
         &self,
         rng: &mut ThreadRng,
-    ) -> Option<ReadGuard<Uuid, Arc<RwLock<BufferedBroadcast>>>> {
+    ) -> Option<ReadGuard<'_, Uuid, Arc<RwLock<BufferedBroadcast>>>> {
         self.send_keys
             .read()
             .unwrap()
@@ -365,14 +411,38 @@ impl GossipProtocol {
             .and_then(|key| self.send_buffer.get(key))
     }
 
-    fn do_broadcast(&self, bytes: Vec<u8>, peers: Vec<NodeMeta>) {
+    /**Returns false if the bandwidth cap put off every send*/
+    fn do_broadcast(&self, payload: &BroadcastMessage, peers: Vec<NodeMeta>) -> bool {
+        let mut sent = false;
+
         for peer in peers.iter() {
-            self.messaging_service.read().unwrap().send_to_member_type(
-                bytes.clone(),
+            if !self.limits.gossip_available() {
+                break;
+            }
+
+            // every peer gets its own portion of membership updates
+            let msg = GossipMessage {
+                broadcast: payload.clone(),
+                updates: self.membership_service.read().unwrap().take_updates(),
+            };
+            let bytes = serialize::to_bytes(&msg).unwrap();
+            self.limits.gossip_sent(bytes.len());
+
+            let result = self.messaging_service.read().unwrap().send_to_member_type(
+                bytes,
                 peer,
                 MessageType::Broadcast,
             );
+            if let Err(_) = result {
+                eprintln!(
+                    "[BroadcastService]: Error while gossiping to {:?}",
+                    peer.addr
+                );
+            }
+            sent = true;
         }
+
+        sent
     }
 
     fn move_to_keep_buffer(&self) {
@@ -380,8 +450,8 @@ impl GossipProtocol {
             if let Some(br) = self.send_buffer.get(key) {
                 if br.read().unwrap().rounds < 0 {
                     br.write().unwrap().send = false;
-                    self.keep_buffer.insert(key.clone(), br.clone());
-                    self.keep_keys.write().unwrap().push(key.clone())
+                    self.keep_buffer.insert(*key, br.clone());
+                    self.keep_keys.write().unwrap().push(*key)
                 }
             }
         }
@@ -408,18 +478,40 @@ impl GossipProtocol {
             .retain(|key| self.keep_buffer.contains_key(key));
     }
 
-    pub fn add_listener<F>(&self, f: F) -> Result<(), ()>
+    pub fn add_listener<F>(&self, f: F) -> Subscription
     where
-        F: Fn(Arc<BroadcastMessage>) -> () + 'static + Send + Sync,
+        F: Fn(Arc<BroadcastMessage>) + 'static + Send + Sync,
     {
-        self.listeners.write().unwrap().push(Box::new(f));
-        Ok(())
+        let queue = self
+            .event_loop
+            .listener_queue(move |broadcast: Arc<BroadcastMessage>| {
+                let context = broadcast
+                    .traceparent
+                    .as_ref()
+                    .and_then(|value| TraceContext::from_traceparent(value));
+                let _context = trace::enter(context);
+                let span = tracing::debug_span!("hover.handle", broadcast = %broadcast.id);
+                let _span = span.enter();
+
+                f(broadcast)
+            });
+        self.listeners.add(queue.clone()).cancelling(queue)
+    }
+
+    /**Channel of received broadcasts. It is unregistered once the receiver is dropped*/
+    pub fn subscribe(&self) -> Receiver<Arc<BroadcastMessage>> {
+        self.event_loop.subscribe(&self.listeners)
+    }
+
+    pub fn subscribe_stream(&self) -> SubscriptionStream<Arc<BroadcastMessage>> {
+        self.event_loop.subscribe_stream(&self.listeners)
     }
 
     fn notify_listeners(&self, payload: BroadcastMessage) {
         let payload = Arc::new(payload);
-        for listener in self.listeners.read().unwrap().iter() {
-            listener(payload.clone());
+        let event_loop = &self.event_loop;
+        for listener in self.listeners.snapshot().iter() {
+            event_loop.dispatch(listener, payload.clone());
         }
     }
 }
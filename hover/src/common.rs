@@ -1,5 +1,10 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
 use std::net::*;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::coordinate::Coordinate;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -22,36 +27,281 @@ pub enum MessageType {
     Probe = 2,
     ProbeReq = 3,
     Broadcast = 4,
+    Rpc = 5,
+    /**Response of a request that could not be handled*/
+    ErrorResponse = 6,
+    /**Chunk of a streamed transfer*/
+    StreamChunk = 7,
+    /**Message of a reliable channel. Answered with a ReliableAck*/
+    Reliable = 8,
+}
+
+/**Priority class of a message. Protocol messages are handled first,
+user messages get the remaining share of the bandwidth*/
+#[derive(Serialize, Deserialize, PartialEq, Debug, Hash, Clone, Copy)]
+pub enum Priority {
+    Protocol = 0,
+    Broadcast = 1,
+    User = 2,
+}
+
+impl Priority {
+    /**Class of a new message of the type. Responses are sent with
+    the class of the request they answer*/
+    pub fn of(msg_type: &MessageType) -> Priority {
+        match msg_type {
+            MessageType::Probe | MessageType::ProbeReq => Priority::Protocol,
+            MessageType::Broadcast => Priority::Broadcast,
+            _ => Priority::User,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Hash)]
 pub struct Message {
     pub cor_id: Uuid,
     pub msg_type: MessageType,
+    pub priority: Priority,
+    /**Node that sent the message*/
+    pub sender_id: Uuid,
+    /**Extension headers, e.g. content-type or trace ids*/
+    pub headers: BTreeMap<String, String>,
+    /**How long the sender waits for the response*/
+    pub timeout_ms: Option<u64>,
+    /**Set by the receiving node*/
+    #[serde(skip)]
+    pub received_at: Option<Instant>,
     pub payload: Vec<u8>,
     pub return_address: Address,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Hash)]
-enum ConnectionMessageType {
-    Try = 0,
-    Ok = 1,
-    Duplicate = 2,
+impl Message {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|value| value.as_str())
+    }
+
+    /**Point in time after which the sender no longer waits for the response.
+    It is counted from the moment the message was received, so clocks of the nodes
+    do not have to agree. None if the timeout is too long to be represented*/
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.received_at, self.timeout_ms) {
+            (Some(received_at), Some(timeout_ms)) => {
+                received_at.checked_add(Duration::from_millis(timeout_ms))
+            }
+            _ => None,
+        }
+    }
+
+    /**Time the sender is still willing to wait for the response*/
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /**Response would arrive after the sender gave up*/
+    pub fn is_expired(&self) -> bool {
+        match self.deadline() {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Hash)]
-struct ConnectionMessage {
-    r#type: ConnectionMessageType,
-    node_id: String,
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum MemberStatus {
+    Alive = 0,
+    Suspect = 1,
+    Dead = 2,
+    Left = 3,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Hash)]
+/**Member as seen by the local node*/
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Member {
+    pub node: NodeMeta,
+    pub status: MemberStatus,
+    pub incarnation: u64,
+    pub tags: HashMap<String, String>,
+    pub joined_at: SystemTime,
+    /**Last time the member answered a direct or an indirect probe*/
+    pub last_probed_at: Option<SystemTime>,
+    /**RTT of the last direct probe. Indirect probes go through another member,
+    so they do not measure it*/
+    pub last_rtt: Option<Duration>,
+}
+
+/**Membership change piggybacked onto protocol messages*/
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct MembershipUpdate {
+    pub node: NodeMeta,
+    pub status: MemberStatus,
+    pub incarnation: u64,
+    pub tags: HashMap<String, String>,
+    // node that issued the update
+    pub from: Uuid,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ProbePayload {
+    pub updates: Vec<MembershipUpdate>,
+}
+
+/**Response to probes and probe requests. Nack means the probe request was received
+but the requested node did not answer. Carries the network coordinate of the responder*/
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct AckPayload {
+    pub nack: bool,
+    pub updates: Vec<MembershipUpdate>,
+    pub coordinate: Coordinate,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct ProbeReqPayload {
     pub node: NodeMeta,
+    pub updates: Vec<MembershipUpdate>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Hash, Clone)]
 pub struct BroadcastMessage {
     pub id: Uuid,
     pub payload: Vec<u8>,
+    /**Trace context of the node that started the broadcast*/
+    pub traceparent: Option<String>,
+}
+
+/**Gossip message sent between peers*/
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct GossipMessage {
+    pub broadcast: BroadcastMessage,
+    pub updates: Vec<MembershipUpdate>,
+}
+
+/**Remote procedure call routed by the method name*/
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RpcRequest {
+    pub method: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RpcResponse {
+    pub result: Result<Vec<u8>, RpcError>,
+}
+
+/**Message sent over a reliable channel. Sequence numbers are scoped by the channel*/
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ReliableMessage {
+    pub channel: Uuid,
+    pub seq: u64,
+    pub payload: Vec<u8>,
+}
+
+/**Response to a reliable message*/
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum ReliableAck {
+    /**Message was delivered, now or by an earlier transmission*/
+    Delivered,
+    /**No listener took the message. Receiver still expects its sequence number*/
+    Refused,
+    /**Message is not the next one of the channel, an earlier one was lost*/
+    OutOfOrder,
+}
+
+/**Part of a streamed transfer. Offset is the position of the data in the stream*/
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct StreamChunk {
+    pub stream_id: Uuid,
+    pub offset: u64,
+    pub data: Vec<u8>,
+    pub last: bool,
+}
+
+/**Response to a chunk. Offset is the next one the receiver expects. Busy means the
+chunk was not taken because the receiver's window is full. Closed means the receiver
+does not accept the stream anymore*/
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct StreamAck {
+    pub offset: u64,
+    pub busy: bool,
+    pub closed: bool,
+}
+
+/**Failure of a remote procedure call*/
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum RpcError {
+    /**No handler is registered for the method on the remote node*/
+    UnknownMethod(String),
+    /**Handler of the method returned an error*/
+    HandlerFailed(String),
+    /**No response within the timeout*/
+    Timeout,
+    /**Request or response could not be encoded or decoded*/
+    Codec(String),
+    /**Request could not be delivered*/
+    Transport(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcError::UnknownMethod(method) => write!(f, "Unknown method: {}", method),
+            RpcError::HandlerFailed(reason) => write!(f, "Handler failed: {}", reason),
+            RpcError::Timeout => write!(f, "Timed out waiting for the response"),
+            RpcError::Codec(reason) => write!(f, "Codec error: {}", reason),
+            RpcError::Transport(reason) => write!(f, "Transport error: {}", reason),
+        }
+    }
+}
+
+impl Error for RpcError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(received_ago: Duration, timeout_ms: Option<u64>) -> Message {
+        Message {
+            cor_id: Uuid::new_v4(),
+            msg_type: MessageType::Rpc,
+            priority: Priority::User,
+            sender_id: Uuid::new_v4(),
+            headers: BTreeMap::new(),
+            timeout_ms,
+            received_at: Some(Instant::now() - received_ago),
+            payload: Vec::new(),
+            return_address: Address {
+                ip: Ipv4Addr::LOCALHOST,
+                port: 7000,
+            },
+        }
+    }
+
+    #[test]
+    fn remaining_time_decreases() {
+        let msg = message(Duration::from_millis(0), Some(1000));
+        let first = msg.remaining().unwrap();
+        assert!(first <= Duration::from_millis(1000));
+
+        std::thread::sleep(Duration::from_millis(20));
+        let second = msg.remaining().unwrap();
+        assert!(second <= first - Duration::from_millis(20));
+        assert!(!msg.is_expired());
+    }
+
+    #[test]
+    fn message_expires_at_deadline() {
+        let msg = message(Duration::from_millis(200), Some(100));
+        assert_eq!(msg.remaining(), Some(Duration::from_millis(0)));
+        assert!(msg.is_expired());
+    }
+
+    #[test]
+    fn message_without_timeout_never_expires() {
+        let msg = message(Duration::from_secs(60), None);
+        assert_eq!(msg.remaining(), None);
+        assert!(!msg.is_expired());
+
+        let msg = message(Duration::from_secs(0), Some(u64::MAX));
+        assert!(!msg.is_expired());
+    }
 }
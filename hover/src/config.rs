@@ -1,5 +1,15 @@
-use config::{ConfigError, FileFormat};
+use config::ConfigError;
 use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::serialize::CodecKind;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureDetectorKind {
+    Swim,
+    Phi,
+}
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct DiscoveryConfig {
@@ -9,6 +19,22 @@ pub struct DiscoveryConfig {
     pub rate_ms: u64,
     pub probe_timeout_ms: u64,
     pub probe_req_timeout_ms: u64,
+    pub retransmit_mult: u32,
+    pub suspicion_mult: u32,
+    pub suspicion_max_timeout_mult: u32,
+    pub awareness_max_multiplier: u32,
+    pub tombstone_reap_ms: u64,
+    pub partition_window_ms: u64,
+    pub partition_threshold: f64,
+    /**Suspected partition that did not heal within this period is accepted as the
+    new cluster size*/
+    pub partition_rebaseline_ms: u64,
+    pub failure_detector: FailureDetectorKind,
+    pub phi_threshold: f64,
+    pub phi_window_size: usize,
+    pub phi_min_std_deviation_ms: u64,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -18,15 +44,108 @@ pub struct BroadcastConfig {
     pub message_keep: i32,
 }
 
+/**Retries and circuit breakers of user messages. Protocol messages are never retried*/
+#[derive(Debug, Deserialize, Clone)]
+pub struct MessagingConfig {
+    /**Attempts of a user send, including the first one. Retries are off by default*/
+    pub retry_max_attempts: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub retry_jitter: f64,
+    pub retry_idempotent_only: bool,
+    pub breaker_failure_threshold: u32,
+    pub breaker_open_ms: u64,
+    /**Reliable messages are retransmitted if not acknowledged within the timeout*/
+    pub reliable_ack_timeout_ms: u64,
+    pub reliable_max_attempts: u32,
+}
+
+/**Chunked transfers of large payloads*/
+#[derive(Debug, Deserialize, Clone)]
+pub struct StreamConfig {
+    pub chunk_size: usize,
+    /**Chunks buffered by the receiver before the sender has to wait*/
+    pub window: usize,
+    pub ack_timeout_ms: u64,
+    /**Incoming stream fails if no chunk arrives for this long*/
+    pub idle_timeout_ms: u64,
+    /**Incoming streams handled at a time. Further ones wait*/
+    pub max_concurrent: usize,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /**Items that do not fit are dropped and counted*/
+    Drop,
+    /**Offering an item waits for room in the queue, up to `block_timeout_ms`.
+    Items that still do not fit are dropped and logged*/
+    Block,
+    /**Like drop, and every dropped item is logged*/
+    Log,
+}
+
+/**Priority lanes of incoming and outgoing messages. Shares are relative weights of
+the lanes*/
+#[derive(Debug, Deserialize, Clone)]
+pub struct LaneConfig {
+    pub protocol_share: u32,
+    pub broadcast_share: u32,
+    pub user_share: u32,
+    /**Threads reading incoming connections. Connections of a source address are
+    read by the same thread*/
+    pub reader_threads: usize,
+    /**Threads sending outgoing messages*/
+    pub sender_threads: usize,
+    /**Items a lane holds. Further ones are dropped and counted*/
+    pub queue_size: usize,
+    /**Accepted connections waiting for each reader thread. Further ones are closed
+    unread and counted*/
+    pub pending_connections: usize,
+}
+
+/**Limits of incoming traffic per source address and of outgoing gossip.
+Rates of zero disable the limit*/
+#[derive(Debug, Deserialize, Clone)]
+pub struct LimitConfig {
+    pub inbound_messages_per_sec: u64,
+    pub inbound_message_burst: u64,
+    pub inbound_bytes_per_sec: u64,
+    pub inbound_byte_burst: u64,
+    /**Larger messages are dropped. Zero disables the limit*/
+    pub max_message_bytes: u64,
+    pub gossip_bytes_per_sec: u64,
+    pub gossip_byte_burst: u64,
+}
+
+/**Queues of user callbacks*/
+#[derive(Debug, Deserialize, Clone)]
+pub struct ListenerConfig {
+    pub queue_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    /**How long the block policy waits for room in the queue*/
+    pub block_timeout_ms: u64,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct HoverConfig {
     pub address: String,
     pub port: u16,
+    /**Codec of the message envelopes. All nodes of a cluster should use the same one*/
+    pub codec: CodecKind,
     pub discovery: DiscoveryConfig,
     pub broadcast: BroadcastConfig,
+    pub messaging: MessagingConfig,
+    pub streams: StreamConfig,
+    pub lanes: LaneConfig,
+    pub limits: LimitConfig,
+    pub listeners: ListenerConfig,
+    /**Channels returned by the subscribe_* methods*/
+    pub subscriptions: ListenerConfig,
 }
 
 impl HoverConfig {
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Result<HoverConfig, ConfigError> {
         let mut conf = config::Config::default();
 
@@ -48,6 +167,7 @@ impl HoverConfig {
 fn apply_default(conf: &mut config::Config) {
     conf.set_default("address", "127.0.0.1").unwrap();
     conf.set_default("port", "6202").unwrap();
+    conf.set_default("codec", "bincode").unwrap();
     conf.set_default("discovery.multicast_group", "228.0.0.1")
         .unwrap();
     conf.set_default("discovery.multicast_port", "2403")
@@ -58,7 +178,84 @@ fn apply_default(conf: &mut config::Config) {
         .unwrap();
     conf.set_default("discovery.probe_req_timeout_ms", "700")
         .unwrap();
+    conf.set_default("discovery.retransmit_mult", "3").unwrap();
+    conf.set_default("discovery.suspicion_mult", "4").unwrap();
+    conf.set_default("discovery.suspicion_max_timeout_mult", "6")
+        .unwrap();
+    conf.set_default("discovery.awareness_max_multiplier", "8")
+        .unwrap();
+    conf.set_default("discovery.tombstone_reap_ms", "30000")
+        .unwrap();
+    conf.set_default("discovery.partition_window_ms", "60000")
+        .unwrap();
+    conf.set_default("discovery.partition_threshold", "0.5")
+        .unwrap();
+    conf.set_default("discovery.partition_rebaseline_ms", "600000")
+        .unwrap();
+    conf.set_default("discovery.failure_detector", "swim")
+        .unwrap();
+    conf.set_default("discovery.phi_threshold", "8").unwrap();
+    conf.set_default("discovery.phi_window_size", "100")
+        .unwrap();
+    conf.set_default("discovery.phi_min_std_deviation_ms", "100")
+        .unwrap();
     conf.set_default("broadcast.fanout", "3").unwrap();
     conf.set_default("broadcast.rate_ms", "500").unwrap();
     conf.set_default("broadcast.message_keep", "100").unwrap();
+    conf.set_default("messaging.retry_max_attempts", "1")
+        .unwrap();
+    conf.set_default("messaging.retry_base_delay_ms", "50")
+        .unwrap();
+    conf.set_default("messaging.retry_max_delay_ms", "2000")
+        .unwrap();
+    conf.set_default("messaging.retry_jitter", "0.5").unwrap();
+    conf.set_default("messaging.retry_idempotent_only", "true")
+        .unwrap();
+    conf.set_default("messaging.breaker_failure_threshold", "5")
+        .unwrap();
+    conf.set_default("messaging.breaker_open_ms", "30000")
+        .unwrap();
+    conf.set_default("messaging.reliable_ack_timeout_ms", "1000")
+        .unwrap();
+    conf.set_default("messaging.reliable_max_attempts", "5")
+        .unwrap();
+    conf.set_default("streams.chunk_size", "65536").unwrap();
+    conf.set_default("streams.window", "16").unwrap();
+    conf.set_default("streams.ack_timeout_ms", "5000").unwrap();
+    conf.set_default("streams.idle_timeout_ms", "30000")
+        .unwrap();
+    conf.set_default("streams.max_concurrent", "16").unwrap();
+    conf.set_default("lanes.protocol_share", "70").unwrap();
+    conf.set_default("lanes.broadcast_share", "20").unwrap();
+    conf.set_default("lanes.user_share", "10").unwrap();
+    conf.set_default("lanes.reader_threads", "4").unwrap();
+    conf.set_default("lanes.sender_threads", "8").unwrap();
+    conf.set_default("lanes.queue_size", "4096").unwrap();
+    conf.set_default("lanes.pending_connections", "256")
+        .unwrap();
+    conf.set_default("limits.inbound_messages_per_sec", "2000")
+        .unwrap();
+    conf.set_default("limits.inbound_message_burst", "4000")
+        .unwrap();
+    conf.set_default("limits.inbound_bytes_per_sec", "16777216")
+        .unwrap();
+    conf.set_default("limits.inbound_byte_burst", "33554432")
+        .unwrap();
+    conf.set_default("limits.max_message_bytes", "33554432")
+        .unwrap();
+    conf.set_default("limits.gossip_bytes_per_sec", "1048576")
+        .unwrap();
+    conf.set_default("limits.gossip_byte_burst", "2097152")
+        .unwrap();
+    conf.set_default("listeners.queue_size", "1024").unwrap();
+    conf.set_default("listeners.overflow_policy", "log")
+        .unwrap();
+    conf.set_default("listeners.block_timeout_ms", "1000")
+        .unwrap();
+    conf.set_default("subscriptions.queue_size", "1024")
+        .unwrap();
+    conf.set_default("subscriptions.overflow_policy", "log")
+        .unwrap();
+    conf.set_default("subscriptions.block_timeout_ms", "1000")
+        .unwrap();
 }
@@ -1,40 +1,90 @@
 extern crate socket2;
 
-use std::cell::RefCell;
-use std::net::{Ipv4Addr, TcpListener};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex, RwLock};
-
-use self::socket2::Socket;
-use crate::common::{Address, Message, MessageType, NodeMeta};
-use crate::events::{Event, EventListener, EventLoop};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::common::{Message, NodeMeta};
+use crate::config::LaneConfig;
+use crate::events::EventLoop;
+use crate::limits::Limits;
 use crate::serialize;
+use crate::serialize::CodecKind;
+use crossbeam_channel::Sender;
 
 use std::error::Error;
 use std::io;
 use std::io::Read;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/**Longest wait for the next bytes of an incoming message*/
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/**Longest time to read a whole incoming message, however steadily its bytes come in*/
+const MESSAGE_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const READ_CHUNK: usize = 8192;
+
+/**Messages encoded with another codec than the local one. They are all counted,
+but each source is logged only once*/
+struct ForeignCodec {
+    messages: AtomicU64,
+    reported: Mutex<HashSet<IpAddr>>,
+}
+
+impl ForeignCodec {
+    fn record(&self, source: IpAddr, codec: CodecKind, local_codec: CodecKind) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        if self.reported.lock().unwrap().insert(source) {
+            eprintln!(
+                "[ConnectionService]: Messages of {} are encoded with {:?}, local codec is {:?}",
+                source, codec, local_codec
+            );
+        }
+    }
+}
 
 /**Connection service*/
 pub struct ConnectionService {
     local_node_meta: NodeMeta,
+    config: LaneConfig,
+    codec: CodecKind,
     running: Arc<AtomicBool>,
     worker_thread_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
-    event_loop: Arc<RwLock<EventLoop>>,
+    limits: Arc<Limits>,
+    foreign_codec: Arc<ForeignCodec>,
+    dropped_connections: Arc<AtomicU64>,
+    event_loop: Arc<EventLoop>,
 }
 
 impl ConnectionService {
-    pub fn new(local_node_meta: NodeMeta, event_loop: Arc<RwLock<EventLoop>>) -> ConnectionService {
+    pub(crate) fn new(
+        local_node_meta: NodeMeta,
+        config: LaneConfig,
+        codec: CodecKind,
+        limits: Arc<Limits>,
+        event_loop: Arc<EventLoop>,
+    ) -> ConnectionService {
         ConnectionService {
             local_node_meta,
+            config,
+            codec,
             running: Arc::new(AtomicBool::default()),
             worker_thread_handle: Arc::new(Mutex::new(Option::None)),
+            limits,
+            foreign_codec: Arc::new(ForeignCodec {
+                messages: AtomicU64::new(0),
+                reported: Mutex::new(HashSet::new()),
+            }),
+            dropped_connections: Arc::new(AtomicU64::new(0)),
             event_loop,
         }
     }
 
     pub fn start(&self) {
-        let running = self.running.store(true, Ordering::Relaxed);
+        self.running.store(true, Ordering::Relaxed);
 
         let tcp_listener = self
             .build_inbound_socket(self.local_node_meta.addr.port)
@@ -50,43 +100,53 @@ impl ConnectionService {
         println!("[ConnectionService]: Started");
     }
 
+    /**Messages read although they were encoded with another codec than the local one*/
+    pub fn foreign_codec_messages(&self) -> u64 {
+        self.foreign_codec.messages.load(Ordering::Relaxed)
+    }
+
+    /**Connections closed unread because the queue of their reader thread was full*/
+    pub fn dropped_connections(&self) -> u64 {
+        self.dropped_connections.load(Ordering::Relaxed)
+    }
+
     fn build_inbound_socket(&self, port: u16) -> io::Result<TcpListener> {
         TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
     }
 
-    fn listen(&self, tcp_listener: TcpListener) -> Result<JoinHandle<()>, Box<Error>> {
+    fn listen(&self, tcp_listener: TcpListener) -> Result<JoinHandle<()>, Box<dyn Error>> {
         let running_ = self.running.clone();
-        let loop_ = self.event_loop.clone();
+        let dropped_ = self.dropped_connections.clone();
+        //connections are read on a pool, so a large message does not hold up other
+        //sources. A source is always read by the same thread, keeping its order
+        let readers: Vec<Sender<(TcpStream, IpAddr)>> = (0..self.config.reader_threads.max(1))
+            .map(|_| {
+                let (s, r) = crossbeam_channel::bounded::<(TcpStream, IpAddr)>(
+                    self.config.pending_connections.max(1),
+                );
+                let loop_ = self.event_loop.clone();
+                let limits = self.limits.clone();
+                let foreign_codec = self.foreign_codec.clone();
+                let codec = self.codec;
+                std::thread::spawn(move || {
+                    for (stream, source) in r.iter() {
+                        read_message(stream, source, codec, &limits, &foreign_codec, &loop_);
+                    }
+                });
+                s
+            })
+            .collect();
 
         //create a connection thread
         let thread_handle = std::thread::spawn(move || {
             while running_.load(Ordering::Relaxed) {
-                let stream = tcp_listener.accept();
-                let mut buff: Vec<u8> = Vec::new();
-
-                match stream {
-                    Ok((mut stream, addr)) => match stream.read_to_end(&mut buff) {
-                        Ok(size) if size > 0 => {
-                            match serialize::from_bytes(buff.as_mut_slice()) {
-                                Ok(msg) => {
-                                    println!(
-                                        "[ConnectionService]: Has read the message: {:?}",
-                                        msg
-                                    );
-                                    let event = Event::MessageIn { msg: Arc::new(msg) };
-
-                                    loop_.read().unwrap().post_event(event);
-                                }
-                                Err(_) => {
-                                    eprintln!("[ConnectionService]: Error while reading message structure");
-                                }
-                            };
-                        }
-                        Err(_) => {}
-                        _ => {
-                            println!("[ConnectionService]: Read 0 bytes");
+                match tcp_listener.accept() {
+                    Ok((stream, addr)) => {
+                        let reader = reader_index(addr.ip(), readers.len());
+                        if let Err(_) = readers[reader].try_send((stream, addr.ip())) {
+                            dropped_.fetch_add(1, Ordering::Relaxed);
                         }
-                    },
+                    }
                     Err(_) => {
                         eprintln!("[ConnectionService]: Failed to start listener");
                     }
@@ -97,3 +157,124 @@ impl ConnectionService {
         Ok(thread_handle)
     }
 }
+
+/**Reads until the peer closes the connection. A peer that stops sending must not
+hold the reader, and neither may one that trickles its bytes: every read waits at
+most READ_TIMEOUT and the whole message at most the budget*/
+fn read_within(
+    stream: &TcpStream,
+    limit: u64,
+    budget: Duration,
+    buff: &mut Vec<u8>,
+) -> io::Result<usize> {
+    let deadline = Instant::now() + budget;
+    let mut reader = stream.take(limit);
+    let mut chunk = [0u8; READ_CHUNK];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Message was not read in time",
+            ));
+        }
+
+        stream.set_read_timeout(Some(remaining.min(READ_TIMEOUT)))?;
+        match reader.read(&mut chunk) {
+            Ok(0) => return Ok(buff.len()),
+            Ok(size) => buff.extend_from_slice(&chunk[..size]),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            // the read waited until the deadline, the next round reports it
+            Err(ref err)
+                if (err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut)
+                    && Instant::now() >= deadline => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn reader_index(source: IpAddr, readers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    (hasher.finish() % readers as u64) as usize
+}
+
+/**Posts the message to the lane of its priority. Messages of nodes configured
+with another codec are still read, but counted. Messages over the limits of
+their source, or not read within MESSAGE_READ_TIMEOUT, are dropped*/
+fn read_message(
+    stream: TcpStream,
+    source: IpAddr,
+    local_codec: CodecKind,
+    limits: &Limits,
+    foreign_codec: &ForeignCodec,
+    event_loop: &EventLoop,
+) {
+    let mut buff: Vec<u8> = Vec::new();
+
+    match read_within(
+        &stream,
+        limits.read_limit(),
+        MESSAGE_READ_TIMEOUT,
+        &mut buff,
+    ) {
+        Ok(size) if size > 0 && !limits.admit_bytes(source, size) => {}
+        Ok(size) if size > 0 => {
+            match serialize::from_frame::<Message>(buff.as_slice()) {
+                Ok((msg, _)) if !limits.admit_message(source, msg.priority) => {}
+                Ok((mut msg, codec)) => {
+                    if codec != local_codec {
+                        foreign_codec.record(source, codec, local_codec);
+                    }
+                    msg.received_at = Some(Instant::now());
+
+                    // dropped messages are counted by the event loop
+                    if let Err(err) = event_loop.post_message(Arc::new(msg)) {
+                        eprintln!(
+                            "[ConnectionService]: Dropped message of {}: {}",
+                            source, err
+                        );
+                    }
+                }
+                Err(_) => {
+                    eprintln!("[ConnectionService]: Error while reading message structure");
+                }
+            };
+        }
+        Err(_) => {}
+        _ => {
+            println!("[ConnectionService]: Read 0 bytes");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn trickling_sender_is_cut_off_at_the_deadline() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut sender = TcpStream::connect(address).unwrap();
+            for _ in 0..100 {
+                if sender.write_all(&[0]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        let mut buff = Vec::new();
+        let result = read_within(&stream, u64::MAX, Duration::from_millis(200), &mut buff);
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,294 @@
+extern crate rand;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use self::rand::Rng;
+use uuid::Uuid;
+
+const DIMENSIONALITY: usize = 8;
+const VIVALDI_ERROR_MAX: f64 = 1.5;
+const VIVALDI_CE: f64 = 0.25;
+const VIVALDI_CC: f64 = 0.25;
+const HEIGHT_MIN: f64 = 10.0e-6;
+const ZERO_THRESHOLD: f64 = 1.0e-6;
+/**RTT samples per node the median is taken of*/
+const LATENCY_FILTER_SIZE: usize = 3;
+/**How strongly coordinates are pulled back to the origin, in seconds*/
+const GRAVITY_RHO: f64 = 150.0;
+
+/**Vivaldi network coordinate. Distance between two coordinates estimates the RTT
+between the nodes in seconds*/
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Coordinate {
+    pub vec: Vec<f64>,
+    pub error: f64,
+    pub height: f64,
+}
+
+impl Default for Coordinate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coordinate {
+    /**Coordinate at the origin with the maximum error*/
+    pub fn new() -> Coordinate {
+        Coordinate {
+            vec: vec![0_f64; DIMENSIONALITY],
+            error: VIVALDI_ERROR_MAX,
+            height: HEIGHT_MIN,
+        }
+    }
+
+    /**Estimated RTT to other coordinate*/
+    pub fn distance_to(&self, other: &Coordinate) -> Duration {
+        let secs = self.raw_distance_to(other);
+        Duration::from_nanos((secs * 1.0e9) as u64)
+    }
+
+    /**Moves the coordinate towards the observed RTT to other node*/
+    pub fn update(&mut self, other: &Coordinate, rtt: Duration) {
+        if !self.is_compatible_with(other) {
+            return;
+        }
+
+        let rtt_secs = to_secs(rtt);
+        if rtt_secs <= ZERO_THRESHOLD {
+            return;
+        }
+
+        let dist = self.raw_distance_to(other);
+        let total_error = (self.error + other.error).max(ZERO_THRESHOLD);
+        let weight = self.error / total_error;
+
+        let wrongness = (dist - rtt_secs).abs() / rtt_secs;
+        self.error = VIVALDI_CE * weight * wrongness + self.error * (1_f64 - VIVALDI_CE * weight);
+        self.error = self.error.min(VIVALDI_ERROR_MAX);
+
+        let force = VIVALDI_CC * weight * (rtt_secs - dist);
+        self.apply_force(force, other);
+    }
+
+    fn apply_force(&mut self, force: f64, other: &Coordinate) {
+        let diff: Vec<f64> = self
+            .vec
+            .iter()
+            .zip(other.vec.iter())
+            .map(|(a, b)| a - b)
+            .collect();
+        let mag = magnitude(&diff);
+
+        let unit = if mag > ZERO_THRESHOLD {
+            diff.iter().map(|d| d / mag).collect()
+        } else {
+            random_unit_vector()
+        };
+
+        for (v, u) in self.vec.iter_mut().zip(unit.iter()) {
+            *v += u * force;
+        }
+
+        if mag > ZERO_THRESHOLD {
+            self.height =
+                ((self.height + other.height) * force / mag + self.height).max(HEIGHT_MIN);
+        }
+    }
+
+    fn raw_distance_to(&self, other: &Coordinate) -> f64 {
+        let diff: Vec<f64> = self
+            .vec
+            .iter()
+            .zip(other.vec.iter())
+            .map(|(a, b)| a - b)
+            .collect();
+
+        magnitude(&diff) + self.height + other.height
+    }
+
+    fn is_compatible_with(&self, other: &Coordinate) -> bool {
+        self.vec.len() == other.vec.len() && other.vec.iter().all(|v| v.is_finite())
+    }
+}
+
+/**Coordinate of the local node and the state it is updated with. RTT samples go
+through a median filter per node, so a single outlier does not move the coordinate.
+A small gravity keeps the coordinates of a cluster from drifting away from the origin*/
+pub(crate) struct CoordinateClient {
+    coordinate: Coordinate,
+    origin: Coordinate,
+    latency_filters: HashMap<Uuid, Vec<f64>>,
+}
+
+impl CoordinateClient {
+    pub(crate) fn new() -> CoordinateClient {
+        CoordinateClient {
+            coordinate: Coordinate::new(),
+            origin: Coordinate::new(),
+            latency_filters: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn coordinate(&self) -> Coordinate {
+        self.coordinate.clone()
+    }
+
+    /**Updates the coordinate with an RTT observed to the node*/
+    pub(crate) fn update(&mut self, node_id: &Uuid, other: &Coordinate, rtt: Duration) {
+        if !self.coordinate.is_compatible_with(other) || to_secs(rtt) <= ZERO_THRESHOLD {
+            return;
+        }
+
+        let rtt = self.latency_filter(node_id, rtt);
+        self.coordinate.update(other, rtt);
+        self.apply_gravity();
+    }
+
+    /**Drops the samples of a node that left*/
+    pub(crate) fn forget(&mut self, node_id: &Uuid) {
+        self.latency_filters.remove(node_id);
+    }
+
+    fn latency_filter(&mut self, node_id: &Uuid, rtt: Duration) -> Duration {
+        let samples = self.latency_filters.entry(*node_id).or_default();
+        samples.push(to_secs(rtt));
+        if samples.len() > LATENCY_FILTER_SIZE {
+            samples.remove(0);
+        }
+
+        let mut sorted = samples.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Duration::from_nanos((sorted[sorted.len() / 2] * 1.0e9) as u64)
+    }
+
+    fn apply_gravity(&mut self) {
+        let dist = self.origin.raw_distance_to(&self.coordinate);
+        let force = -(dist / GRAVITY_RHO).powi(2);
+        self.coordinate.apply_force(force, &self.origin);
+    }
+}
+
+fn to_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1.0e-9
+}
+
+fn magnitude(vec: &[f64]) -> f64 {
+    vec.iter().map(|v| v * v).sum::<f64>().sqrt()
+}
+
+fn random_unit_vector() -> Vec<f64> {
+    let mut rng = rand::thread_rng();
+
+    loop {
+        let vec: Vec<f64> = (0..DIMENSIONALITY)
+            .map(|_| rng.gen::<f64>() - 0.5)
+            .collect();
+        let mag = magnitude(&vec);
+
+        if mag > ZERO_THRESHOLD {
+            return vec.iter().map(|v| v / mag).collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(rtt: Duration) -> f64 {
+        to_secs(rtt) * 1000.0
+    }
+
+    /**Every node is updated with every other one, as if they probed each other*/
+    fn simulate(
+        nodes: &mut [(Uuid, CoordinateClient)],
+        rtt: &dyn Fn(usize, usize) -> f64,
+        rounds: usize,
+    ) {
+        for _ in 0..rounds {
+            for i in 0..nodes.len() {
+                for j in 0..nodes.len() {
+                    if i == j {
+                        continue;
+                    }
+                    let (other_id, other) = (nodes[j].0, nodes[j].1.coordinate());
+                    let sample = Duration::from_nanos((rtt(i, j) * 1.0e6) as u64);
+                    nodes[i].1.update(&other_id, &other, sample);
+                }
+            }
+        }
+    }
+
+    fn cluster(size: usize) -> Vec<(Uuid, CoordinateClient)> {
+        (0..size)
+            .map(|_| (Uuid::new_v4(), CoordinateClient::new()))
+            .collect()
+    }
+
+    #[test]
+    fn coordinates_converge_to_rtt_of_a_line() {
+        let mut nodes = cluster(5);
+        let rtt = |i: usize, j: usize| 10.0 * (i as f64 - j as f64).abs();
+        simulate(&mut nodes, &rtt, 200);
+
+        for i in 0..nodes.len() {
+            for j in 0..nodes.len() {
+                if i == j {
+                    continue;
+                }
+                let estimate = millis(
+                    nodes[i]
+                        .1
+                        .coordinate()
+                        .distance_to(&nodes[j].1.coordinate()),
+                );
+                let expected = rtt(i, j);
+                assert!(
+                    (estimate - expected).abs() / expected < 0.2,
+                    "{} to {}: estimated {}ms, expected {}ms",
+                    i,
+                    j,
+                    estimate,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn single_outlier_does_not_move_coordinate() {
+        let mut nodes = cluster(2);
+        simulate(&mut nodes, &|_, _| 50.0, 100);
+
+        let before = nodes[0].1.coordinate();
+        let (id, other) = (nodes[1].0, nodes[1].1.coordinate());
+        nodes[0].1.update(&id, &other, Duration::from_secs(5));
+        let after = nodes[0].1.coordinate();
+
+        let estimate_before = millis(before.distance_to(&other));
+        let estimate_after = millis(after.distance_to(&other));
+        assert!((estimate_after - estimate_before).abs() < 5.0);
+    }
+
+    #[test]
+    fn gravity_pulls_coordinate_towards_origin() {
+        let mut client = CoordinateClient::new();
+        client.coordinate.vec[0] = 100.0;
+
+        client.apply_gravity();
+        assert!(client.coordinate.vec[0] < 100.0);
+    }
+
+    #[test]
+    fn incompatible_coordinate_is_ignored() {
+        let mut client = CoordinateClient::new();
+        let mut other = Coordinate::new();
+        other.vec.push(1.0);
+
+        client.update(&Uuid::new_v4(), &other, Duration::from_millis(10));
+        assert_eq!(client.coordinate(), Coordinate::new());
+        assert!(client.latency_filters.is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{DiscoveryConfig, FailureDetectorKind};
+use uuid::Uuid;
+
+/**Decides whether a member should be suspected. It is fed with the results of SWIM probes*/
+pub trait FailureDetector {
+    /**Member acknowledged a direct or an indirect probe*/
+    fn heartbeat(&self, member_id: &Uuid);
+
+    /**Member failed both the direct and the indirect probes*/
+    fn probe_failed(&self, member_id: &Uuid);
+
+    fn is_available(&self, member_id: &Uuid) -> bool;
+
+    /**Drops any state kept about the member*/
+    fn forget(&self, member_id: &Uuid);
+}
+
+pub fn from_config(config: &DiscoveryConfig) -> Box<dyn FailureDetector + Send + Sync> {
+    match config.failure_detector {
+        FailureDetectorKind::Swim => Box::new(SwimFailureDetector::new()),
+        FailureDetectorKind::Phi => Box::new(PhiAccrualFailureDetector::new(
+            config.phi_threshold,
+            config.phi_window_size,
+            Duration::from_millis(config.phi_min_std_deviation_ms),
+        )),
+    }
+}
+
+/**Original SWIM rule: a member is failed once it misses a direct and all indirect probes*/
+pub struct SwimFailureDetector {
+    failed: Mutex<HashSet<Uuid>>,
+}
+
+impl Default for SwimFailureDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SwimFailureDetector {
+    pub fn new() -> SwimFailureDetector {
+        SwimFailureDetector {
+            failed: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl FailureDetector for SwimFailureDetector {
+    fn heartbeat(&self, member_id: &Uuid) {
+        self.failed.lock().unwrap().remove(member_id);
+    }
+
+    fn probe_failed(&self, member_id: &Uuid) {
+        self.failed.lock().unwrap().insert(*member_id);
+    }
+
+    fn is_available(&self, member_id: &Uuid) -> bool {
+        !self.failed.lock().unwrap().contains(member_id)
+    }
+
+    fn forget(&self, member_id: &Uuid) {
+        self.failed.lock().unwrap().remove(member_id);
+    }
+}
+
+struct HeartbeatHistory {
+    last: Instant,
+    intervals: VecDeque<f64>,
+}
+
+/**Phi accrual failure detector. Suspicion level grows with time since the last ack
+relative to the observed distribution of intervals between acks*/
+pub struct PhiAccrualFailureDetector {
+    threshold: f64,
+    window_size: usize,
+    min_std_deviation: Duration,
+    history: Mutex<HashMap<Uuid, HeartbeatHistory>>,
+}
+
+impl PhiAccrualFailureDetector {
+    pub fn new(
+        threshold: f64,
+        window_size: usize,
+        min_std_deviation: Duration,
+    ) -> PhiAccrualFailureDetector {
+        PhiAccrualFailureDetector {
+            threshold,
+            window_size,
+            min_std_deviation,
+            history: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**Returns None until at least two acks were received from the member*/
+    pub fn phi(&self, member_id: &Uuid) -> Option<f64> {
+        let history = self.history.lock().unwrap();
+        let h = history.get(member_id)?;
+        if h.intervals.is_empty() {
+            return None;
+        }
+
+        let count = h.intervals.len() as f64;
+        let mean = h.intervals.iter().sum::<f64>() / count;
+        let variance = h
+            .intervals
+            .iter()
+            .map(|i| (i - mean) * (i - mean))
+            .sum::<f64>()
+            / count;
+        let std_deviation = variance.sqrt().max(as_millis(self.min_std_deviation));
+
+        let elapsed = as_millis(h.last.elapsed());
+        Some(phi(elapsed, mean, std_deviation))
+    }
+}
+
+impl FailureDetector for PhiAccrualFailureDetector {
+    fn heartbeat(&self, member_id: &Uuid) {
+        let now = Instant::now();
+        let mut history = self.history.lock().unwrap();
+
+        match history.get_mut(member_id) {
+            Some(h) => {
+                let interval = as_millis(now.duration_since(h.last));
+                h.intervals.push_back(interval);
+                if h.intervals.len() > self.window_size {
+                    h.intervals.pop_front();
+                }
+                h.last = now;
+            }
+            None => {
+                history.insert(
+                    *member_id,
+                    HeartbeatHistory {
+                        last: now,
+                        intervals: VecDeque::new(),
+                    },
+                );
+            }
+        }
+    }
+
+    fn probe_failed(&self, _member_id: &Uuid) {
+        // missing acks are reflected by the growing phi
+    }
+
+    fn is_available(&self, member_id: &Uuid) -> bool {
+        self.phi(member_id)
+            .map(|phi| phi < self.threshold)
+            .unwrap_or(true)
+    }
+
+    fn forget(&self, member_id: &Uuid) {
+        self.history.lock().unwrap().remove(member_id);
+    }
+}
+
+/**Logistic approximation of the normal CDF, as in the Akka implementation*/
+fn phi(elapsed: f64, mean: f64, std_deviation: f64) -> f64 {
+    let y = (elapsed - mean) / std_deviation;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+
+    if elapsed > mean {
+        -(e / (1_f64 + e)).log10()
+    } else {
+        -(1_f64 - 1_f64 / (1_f64 + e)).log10()
+    }
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000_f64 + duration.subsec_nanos() as f64 / 1.0e6
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> PhiAccrualFailureDetector {
+        PhiAccrualFailureDetector::new(8.0, 10, Duration::from_millis(10))
+    }
+
+    fn with_history(
+        detector: &PhiAccrualFailureDetector,
+        id: Uuid,
+        interval_ms: u64,
+        since_ms: u64,
+    ) {
+        detector.history.lock().unwrap().insert(
+            id,
+            HeartbeatHistory {
+                last: Instant::now() - Duration::from_millis(since_ms),
+                intervals: vec![interval_ms as f64; 10].into_iter().collect(),
+            },
+        );
+    }
+
+    #[test]
+    fn phi_at_mean_is_half_probability() {
+        assert!((phi(100.0, 100.0, 10.0) - 2_f64.log10()).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn phi_grows_with_elapsed_time() {
+        let values: Vec<f64> = [50.0, 100.0, 120.0, 150.0]
+            .iter()
+            .map(|elapsed| phi(*elapsed, 100.0, 10.0))
+            .collect();
+
+        assert!(values.windows(2).all(|w| w[0] < w[1]), "{:?}", values);
+    }
+
+    #[test]
+    fn phi_is_none_until_two_heartbeats() {
+        let detector = detector();
+        let id = Uuid::new_v4();
+        assert_eq!(detector.phi(&id), None);
+
+        detector.heartbeat(&id);
+        assert_eq!(detector.phi(&id), None);
+        assert!(detector.is_available(&id));
+
+        detector.heartbeat(&id);
+        assert!(detector.phi(&id).is_some());
+    }
+
+    #[test]
+    fn member_is_unavailable_once_acks_stop() {
+        let detector = detector();
+        let (late, on_time) = (Uuid::new_v4(), Uuid::new_v4());
+        with_history(&detector, late, 100, 1000);
+        with_history(&detector, on_time, 100, 0);
+
+        assert!(!detector.is_available(&late));
+        assert!(detector.is_available(&on_time));
+    }
+
+    #[test]
+    fn forget_drops_history() {
+        let detector = detector();
+        let id = Uuid::new_v4();
+        with_history(&detector, id, 100, 1000);
+
+        detector.forget(&id);
+        assert_eq!(detector.phi(&id), None);
+        assert!(detector.is_available(&id));
+    }
+
+    #[test]
+    fn swim_detector_fails_until_heartbeat() {
+        let detector = SwimFailureDetector::new();
+        let id = Uuid::new_v4();
+
+        detector.probe_failed(&id);
+        assert!(!detector.is_available(&id));
+        detector.heartbeat(&id);
+        assert!(detector.is_available(&id));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::common::NodeMeta;
-use crate::events::{Event, EventListener, EventLoop};
+use crate::events::{Event, EventLoop};
 use crate::membership::MembershipService;
 
 use crate::config::DiscoveryConfig;
@@ -7,21 +7,22 @@ use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
 use std::time::Duration;
 
-/**Send Join and Leave events periodicaly*/
+/**Announces the local node via multicast until it finds any peer.
+Further membership changes are piggybacked onto protocol messages*/
 pub struct DiscoveryProvider {
     local_node_meta: NodeMeta,
     config: DiscoveryConfig,
     worker_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
     membership_service: Arc<RwLock<MembershipService>>,
-    event_loop: Arc<RwLock<EventLoop>>,
+    event_loop: Arc<EventLoop>,
 }
 
 impl DiscoveryProvider {
-    pub fn new(
+    pub(crate) fn new(
         local_node_meta: NodeMeta,
         config: DiscoveryConfig,
         membership_service: Arc<RwLock<MembershipService>>,
-        event_loop: Arc<RwLock<EventLoop>>,
+        event_loop: Arc<EventLoop>,
     ) -> DiscoveryProvider {
         DiscoveryProvider {
             local_node_meta,
@@ -34,17 +35,29 @@ impl DiscoveryProvider {
 
     pub fn start(&self) {
         let loop_ = self.event_loop.clone();
-        let local_join_event = Event::JoinOut {
-            node_meta: self.local_node_meta.clone(),
-        };
+        let membership_ = self.membership_service.clone();
+        let node_meta = self.local_node_meta.clone();
         let rate = self.config.rate_ms;
 
         let thread = std::thread::spawn(move || loop {
-            loop_
-                .write()
-                .unwrap()
-                .post_event(local_join_event.clone())
-                .unwrap();
+            let local_join_event = {
+                let membership = membership_.read().unwrap();
+
+                if membership.get_member_count() == 0 {
+                    let local_update = membership.local_update();
+                    Some(Event::JoinOut {
+                        node_meta: node_meta.clone(),
+                        incarnation: local_update.incarnation,
+                        tags: local_update.tags,
+                    })
+                } else {
+                    None
+                }
+            };
+
+            if let Some(event) = local_join_event {
+                loop_.post_event(event);
+            }
 
             std::thread::sleep(Duration::from_millis(rate))
         });
@@ -53,14 +66,3 @@ impl DiscoveryProvider {
         println!("[DiscoveryProvider]: Started")
     }
 }
-
-impl EventListener for DiscoveryProvider {
-    fn on_event(&self, event: Event) {
-        if let Event::MemberLeft { node_meta } = event {
-            self.event_loop
-                .read()
-                .unwrap()
-                .post_event(Event::LeftOut { node_meta });
-        }
-    }
-}
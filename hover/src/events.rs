@@ -1,26 +1,65 @@
 extern crate crossbeam_channel;
 
+use std::collections::HashMap;
 use std::error::Error;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use crate::common::{Address, BroadcastMessage, Message, NodeMeta};
-use crate::Node;
-use crossbeam_channel::{Receiver, Sender};
+use crate::common::{
+    Address, BroadcastMessage, Member, MembershipUpdate, Message, NodeMeta, Priority,
+};
+use crate::config::{LaneConfig, ListenerConfig, OverflowPolicy};
+use crate::lanes::Lanes;
+use crossbeam_channel::{Receiver, SendTimeoutError, Sender, TryRecvError, TrySendError};
+use futures::task::{AtomicWaker, Context, Poll};
+use futures::Stream;
 use uuid::Uuid;
 
+/**Cluster events delivered to user listeners. New variants may be added
+without a breaking change, so matches must have a wildcard arm*/
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ClusterEvent {
+    MemberAdded {
+        node_meta: NodeMeta,
+    },
+    MemberLeft {
+        node_meta: NodeMeta,
+    },
+    MemberUpdated {
+        node_meta: NodeMeta,
+    },
+    MemberSuspected {
+        node_meta: NodeMeta,
+    },
+    PartitionSuspected {
+        cluster_size: usize,
+        peak_size: usize,
+    },
+    PartitionHealed {
+        cluster_size: usize,
+    },
+}
+
+pub trait ClusterEventListener {
+    fn on_event(&self, event: ClusterEvent);
+}
+
+/**Internal protocol events. They never leave the crate*/
 #[derive(Clone)]
-pub enum Event {
-    Empty,
+pub(crate) enum Event {
     /**Discovery messages*/
     JoinOut {
         node_meta: NodeMeta,
-    },
-    LeftOut {
-        node_meta: NodeMeta,
+        incarnation: u64,
+        tags: HashMap<String, String>,
     },
     JoinIn {
         node_meta: NodeMeta,
+        incarnation: u64,
+        tags: HashMap<String, String>,
     },
     LeftIn {
         node_meta: NodeMeta,
@@ -38,12 +77,29 @@ pub enum Event {
         probe_node: NodeMeta,
         return_address: Address,
     },
-    /**Membership events*/
+    MembershipUpdatesIn {
+        updates: Vec<MembershipUpdate>,
+    },
+    /**Membership events. Member is the state right after the change*/
     MemberAdded {
-        node_meta: NodeMeta,
+        member: Member,
     },
     MemberLeft {
-        node_meta: NodeMeta,
+        member: Member,
+    },
+    MemberUpdated {
+        member: Member,
+    },
+    MemberSuspected {
+        member: Member,
+    },
+    /**Cluster events*/
+    PartitionSuspected {
+        cluster_size: usize,
+        peak_size: usize,
+    },
+    PartitionHealed {
+        cluster_size: usize,
     },
     /**Gossip message in*/
     BroadcastIn {
@@ -51,56 +107,441 @@ pub enum Event {
     },
     BroadcastOut {
         payload: Vec<u8>,
+        traceparent: Option<String>,
     },
 }
 
-pub struct EventLoop {
+impl Event {
+    /**Public counterpart of the event, if users are interested in it*/
+    fn to_cluster_event(&self) -> Option<ClusterEvent> {
+        match self {
+            Event::MemberAdded { member } => Some(ClusterEvent::MemberAdded {
+                node_meta: member.node.clone(),
+            }),
+            Event::MemberLeft { member } => Some(ClusterEvent::MemberLeft {
+                node_meta: member.node.clone(),
+            }),
+            Event::MemberUpdated { member } => Some(ClusterEvent::MemberUpdated {
+                node_meta: member.node.clone(),
+            }),
+            Event::MemberSuspected { member } => Some(ClusterEvent::MemberSuspected {
+                node_meta: member.node.clone(),
+            }),
+            Event::PartitionSuspected {
+                cluster_size,
+                peak_size,
+            } => Some(ClusterEvent::PartitionSuspected {
+                cluster_size: *cluster_size,
+                peak_size: *peak_size,
+            }),
+            Event::PartitionHealed { cluster_size } => Some(ClusterEvent::PartitionHealed {
+                cluster_size: *cluster_size,
+            }),
+            _ => None,
+        }
+    }
+
+    /**Lane of the event. Events raised by the protocol itself are never delayed*/
+    fn priority(&self) -> Priority {
+        match self {
+            Event::MessageIn { msg } => msg.priority,
+            Event::BroadcastIn { .. } | Event::BroadcastOut { .. } => Priority::Broadcast,
+            _ => Priority::Protocol,
+        }
+    }
+
+    /**Bytes accounted to the lane of the event*/
+    fn size(&self) -> usize {
+        match self {
+            Event::MessageIn { msg } => msg.payload.len(),
+            Event::BroadcastIn { payload } => payload.payload.len(),
+            Event::BroadcastOut { payload, .. } => payload.len(),
+            _ => 0,
+        }
+    }
+}
+
+/**Bounded queue with a dedicated worker thread that runs a user callback.
+Items that do not fit are handled according to the overflow policy. Offering an
+item blocks only with the block policy, and at most for its timeout*/
+pub(crate) struct ListenerQueue<T> {
+    sender: Sender<T>,
+    policy: OverflowPolicy,
+    block_timeout: Duration,
+    dropped: Arc<AtomicU64>,
+    cancelled: Arc<AtomicBool>,
+    subscription: Arc<Mutex<Option<Subscription>>>,
+    // wakes the stream reading the queue, if there is one
+    waker: Arc<AtomicWaker>,
+}
+
+impl<T: Send + 'static> ListenerQueue<T> {
+    fn new<F>(config: &ListenerConfig, f: F) -> ListenerQueue<T>
+    where
+        F: Fn(T) + 'static + Send,
+    {
+        let (queue, r) = ListenerQueue::channel(config);
+        let cancelled = queue.cancelled.clone();
+
+        std::thread::spawn(move || {
+            for item in r.iter() {
+                if cancelled.load(Ordering::Relaxed) {
+                    break;
+                }
+                f(item);
+            }
+        });
+
+        queue
+    }
+
+    /**Queue without a worker. Items are consumed by the owner of the receiver*/
+    fn channel(config: &ListenerConfig) -> (ListenerQueue<T>, Receiver<T>) {
+        let (sender, r): (Sender<T>, Receiver<T>) = crossbeam_channel::bounded(config.queue_size);
+
+        let queue = ListenerQueue {
+            sender,
+            policy: config.overflow_policy,
+            block_timeout: Duration::from_millis(config.block_timeout_ms),
+            dropped: Arc::new(AtomicU64::new(0)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            subscription: Arc::new(Mutex::new(None)),
+            waker: Arc::new(AtomicWaker::new()),
+        };
+
+        (queue, r)
+    }
+
+    /**Returns false if the item was dropped*/
+    fn offer(&self, item: T) -> bool {
+        let sent = match self.policy {
+            OverflowPolicy::Block => match self.sender.send_timeout(item, self.block_timeout) {
+                Ok(_) => Ok(()),
+                Err(SendTimeoutError::Timeout(item)) => Err(TrySendError::Full(item)),
+                Err(SendTimeoutError::Disconnected(item)) => Err(TrySendError::Disconnected(item)),
+            },
+            OverflowPolicy::Drop | OverflowPolicy::Log => self.sender.try_send(item),
+        };
+
+        match sent {
+            Ok(_) => {
+                self.waker.wake();
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // the block policy falls back to logging once its timeout is up
+                if let OverflowPolicy::Log | OverflowPolicy::Block = self.policy {
+                    eprintln!(
+                        "[EventLoop]: Listener falls behind. Dropped an item ({} so far)",
+                        dropped
+                    );
+                }
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                // nobody is listening anymore
+                self.subscription.lock().unwrap().take();
+                false
+            }
+        }
+    }
+
+    /**Items still queued are not handled anymore*/
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /**Counts the dropped items with the counter shared by other queues*/
+    fn counting(mut self, dropped: Arc<AtomicU64>) -> ListenerQueue<T> {
+        self.dropped = dropped;
+        self
+    }
+}
+
+/**Handle of a registered listener. The listener is removed when the handle
+is dropped or cancelled. It is safe to cancel it from within the listener itself*/
+#[must_use = "the listener is removed as soon as the subscription is dropped"]
+pub struct Subscription {
+    unsubscribe: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Subscription {
+    pub(crate) fn new<F>(f: F) -> Subscription
+    where
+        F: FnOnce() + 'static + Send,
+    {
+        Subscription {
+            unsubscribe: Some(Box::new(f)),
+        }
+    }
+
+    /**Removes the listener. Items queued for it but not handled yet are discarded*/
+    pub fn cancel(mut self) {
+        self.unsubscribe();
+    }
+
+    /**Keeps an internal listener registered for the lifetime of the node*/
+    pub(crate) fn detach(mut self) {
+        self.unsubscribe.take();
+    }
+
+    /**Also cancels the queue of the listener when unsubscribing*/
+    pub(crate) fn cancelling<T: Send + 'static>(
+        mut self,
+        queue: Arc<ListenerQueue<T>>,
+    ) -> Subscription {
+        let unsubscribe = self.unsubscribe.take();
+        Subscription::new(move || {
+            queue.cancel();
+            if let Some(f) = unsubscribe {
+                f();
+            }
+        })
+    }
+
+    fn unsubscribe(&mut self) {
+        if let Some(f) = self.unsubscribe.take() {
+            f();
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.unsubscribe();
+    }
+}
+
+/**Async variant of a subscription channel. Items are read straight from the queue,
+so a slow consumer is handled by the overflow policy. The channel is unregistered
+as soon as the stream is dropped*/
+pub struct SubscriptionStream<T> {
+    receiver: Receiver<T>,
+    waker: Arc<AtomicWaker>,
+    subscription: Arc<Mutex<Option<Subscription>>>,
+}
+
+impl<T: Send + 'static> SubscriptionStream<T> {
+    pub(crate) fn new(queue: &ListenerQueue<T>, receiver: Receiver<T>) -> SubscriptionStream<T> {
+        SubscriptionStream {
+            receiver,
+            waker: queue.waker.clone(),
+            subscription: queue.subscription.clone(),
+        }
+    }
+}
+
+impl<T> Stream for SubscriptionStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        // registered before reading, so an item offered in between still wakes us
+        self.waker.register(cx.waker());
+
+        match self.receiver.try_recv() {
+            Ok(item) => Poll::Ready(Some(item)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+        }
+    }
+}
+
+impl<T> Drop for SubscriptionStream<T> {
+    fn drop(&mut self) {
+        self.subscription.lock().unwrap().take();
+    }
+}
+
+/**Registered listeners. Every entry is removed by its subscription*/
+pub(crate) struct Listeners<T> {
+    next_id: AtomicUsize,
+    entries: Arc<RwLock<Vec<(usize, T)>>>,
+}
+
+impl<T: Clone + Send + Sync + 'static> Listeners<T> {
+    pub fn new() -> Listeners<T> {
+        Listeners {
+            next_id: AtomicUsize::new(0),
+            entries: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    pub fn add(&self, listener: T) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.entries.write().unwrap().push((id, listener));
+
+        let entries = Arc::downgrade(&self.entries);
+        Subscription::new(move || {
+            if let Some(entries) = entries.upgrade() {
+                entries.write().unwrap().retain(|(i, _)| *i != id);
+            }
+        })
+    }
+
+    /**Copy of the current listeners. No lock is held while they are called,
+    so a listener can cancel its own subscription*/
+    pub fn snapshot(&self) -> Vec<T> {
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(_, l)| l.clone())
+            .collect()
+    }
+}
+
+/**Runs internal listeners on the event loop thread. User callbacks are offered to
+their own listener queues. Only queues with the block policy wait for room, at most
+for their timeout, so callbacks can not hold up protocol processing for long*/
+pub(crate) struct EventLoop {
     atomic_run: Arc<AtomicBool>,
-    sender: Sender<Event>,
-    receiver: Receiver<Event>,
-    listeners: Arc<RwLock<Vec<Arc<RwLock<EventListener + Send + Sync>>>>>,
+    config: ListenerConfig,
+    subscription_config: ListenerConfig,
+    lanes: Arc<Lanes<Event>>,
+    listeners: Arc<Listeners<Arc<RwLock<dyn EventListener + Send + Sync>>>>,
+    user_listeners: Arc<Listeners<Arc<ListenerQueue<ClusterEvent>>>>,
+    // items dropped by every queue created here
+    dropped_items: Arc<AtomicU64>,
 }
 
 impl EventLoop {
-    pub fn new() -> EventLoop {
-        let (s, r): (Sender<Event>, Receiver<Event>) = crossbeam_channel::unbounded();
-
+    pub fn new(
+        config: ListenerConfig,
+        subscription_config: ListenerConfig,
+        lane_config: LaneConfig,
+    ) -> EventLoop {
         EventLoop {
             atomic_run: Arc::new(AtomicBool::default()),
-            sender: s,
-            receiver: r,
-            listeners: Arc::new(RwLock::new(Vec::new())),
+            config,
+            subscription_config,
+            lanes: Arc::new(Lanes::new(&lane_config)),
+            listeners: Arc::new(Listeners::new()),
+            user_listeners: Arc::new(Listeners::new()),
+            dropped_items: Arc::new(AtomicU64::new(0)),
         }
     }
 
+    /**Items dropped by user callback queues and subscription channels that fell behind*/
+    pub fn dropped_items(&self) -> u64 {
+        self.dropped_items.load(Ordering::Relaxed)
+    }
+
+    /**Incoming messages dropped because their lane was full*/
+    pub fn dropped_events(&self) -> u64 {
+        self.lanes.dropped()
+    }
+
+    /**Internal listener. It is called on the event loop thread*/
     pub fn add_listener(
         &self,
-        listener: Arc<RwLock<EventListener + Send + Sync>>,
-    ) -> Result<&EventLoop, Box<Error>> {
-        self.listeners.write().unwrap().push(listener.clone());
-        Ok((self))
+        listener: Arc<RwLock<dyn EventListener + Send + Sync>>,
+    ) -> Subscription {
+        self.listeners.add(listener)
     }
 
-    pub fn post_event(&self, event: Event) -> Result<(), Box<Error>> {
-        match self.sender.send(event) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Box::new(e)),
-        }
+    /**User listener. It is called on its own thread with public events only*/
+    pub fn add_user_listener<T>(&self, listener: T) -> Subscription
+    where
+        T: ClusterEventListener + Send + Sync + 'static,
+    {
+        let queue = self.listener_queue(move |event| listener.on_event(event));
+        self.user_listeners.add(queue.clone()).cancelling(queue)
+    }
+
+    /**Creates a queue for a user callback configured by the listeners config*/
+    pub fn listener_queue<T, F>(&self, f: F) -> Arc<ListenerQueue<T>>
+    where
+        T: Send + 'static,
+        F: Fn(T) + 'static + Send,
+    {
+        Arc::new(ListenerQueue::new(&self.config, f).counting(self.dropped_items.clone()))
+    }
+
+    /**Creates a queue that feeds a channel configured by the subscriptions config*/
+    pub fn channel_queue<T: Send + 'static>(&self) -> (Arc<ListenerQueue<T>>, Receiver<T>) {
+        let (queue, receiver) = ListenerQueue::channel(&self.subscription_config);
+        (
+            Arc::new(queue.counting(self.dropped_items.clone())),
+            receiver,
+        )
+    }
+
+    /**Registers a channel queue. It is unregistered with the first item offered after
+    its receiver is dropped. Streams are unregistered right away*/
+    pub fn register_channel<T>(
+        &self,
+        listeners: &Listeners<Arc<ListenerQueue<T>>>,
+        queue: Arc<ListenerQueue<T>>,
+    ) where
+        T: Send + 'static,
+    {
+        let subscription = listeners.add(queue.clone());
+        *queue.subscription.lock().unwrap() = Some(subscription);
+    }
+
+    pub fn subscribe<T>(&self, listeners: &Listeners<Arc<ListenerQueue<T>>>) -> Receiver<T>
+    where
+        T: Send + 'static,
+    {
+        let (queue, receiver) = self.channel_queue();
+        self.register_channel(listeners, queue);
+        receiver
+    }
+
+    /**Async variant of subscribe*/
+    pub fn subscribe_stream<T>(
+        &self,
+        listeners: &Listeners<Arc<ListenerQueue<T>>>,
+    ) -> SubscriptionStream<T>
+    where
+        T: Send + 'static,
+    {
+        let (queue, receiver) = self.channel_queue();
+        self.register_channel(listeners, queue.clone());
+        SubscriptionStream::new(&queue, receiver)
+    }
+
+    /**Hands an item over to a user callback queue. Blocks only for queues with the
+    block policy, at most for their timeout. Returns false if the queue dropped it*/
+    pub fn dispatch<T: Send + 'static>(&self, queue: &Arc<ListenerQueue<T>>, item: T) -> bool {
+        queue.offer(item)
+    }
+
+    /**Internal events are never dropped, however far the loop falls behind*/
+    pub fn post_event(&self, event: Event) {
+        let priority = event.priority();
+        let size = event.size();
+        self.lanes.push_unbounded(priority, event, size)
+    }
+
+    /**Posts a message read from the network. Fails if the lane of its priority is
+    full, the message is then dropped and counted*/
+    pub fn post_message(&self, msg: Arc<Message>) -> Result<(), Box<dyn Error>> {
+        let event = Event::MessageIn { msg };
+        let priority = event.priority();
+        let size = event.size();
+        self.lanes.push(priority, event, size)
     }
 
     pub fn start(&self) {
         self.atomic_run.store(true, Ordering::Relaxed);
 
         let running_ = self.atomic_run.clone();
-        let receiver_ = self.receiver.clone();
+        let lanes_ = self.lanes.clone();
         let listeners_ = self.listeners.clone();
+        let user_listeners_ = self.user_listeners.clone();
 
         std::thread::spawn(move || {
             while running_.load(Ordering::Relaxed) {
-                for event in receiver_.iter() {
-                    let l_ = listeners_.read().unwrap();
-                    for listener in l_.iter() {
-                        listener.read().unwrap().on_event(event.clone());
+                let event = lanes_.recv();
+                for listener in listeners_.snapshot().iter() {
+                    listener.read().unwrap().on_event(event.clone());
+                }
+
+                if let Some(cluster_event) = event.to_cluster_event() {
+                    for queue in user_listeners_.snapshot().iter() {
+                        queue.offer(cluster_event.clone());
                     }
                 }
             }
@@ -108,6 +549,190 @@ impl EventLoop {
     }
 }
 
-pub trait EventListener {
+pub(crate) trait EventListener {
     fn on_event(&self, event: Event);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    fn config(overflow_policy: OverflowPolicy) -> ListenerConfig {
+        ListenerConfig {
+            queue_size: 1,
+            overflow_policy,
+            block_timeout_ms: 1000,
+        }
+    }
+
+    fn registered(
+        listeners: &Listeners<Arc<ListenerQueue<u32>>>,
+        policy: OverflowPolicy,
+    ) -> (Arc<ListenerQueue<u32>>, Receiver<u32>) {
+        let (queue, receiver) = ListenerQueue::channel(&config(policy));
+        let queue = Arc::new(queue);
+        let subscription = listeners.add(queue.clone());
+        *queue.subscription.lock().unwrap() = Some(subscription);
+        (queue, receiver)
+    }
+
+    #[test]
+    fn drop_policy_drops_and_counts_items_that_do_not_fit() {
+        let (queue, receiver) = ListenerQueue::channel(&config(OverflowPolicy::Drop));
+
+        assert!(queue.offer(1));
+        assert!(!queue.offer(2));
+        assert!(!queue.offer(3));
+
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(receiver.try_iter().collect::<Vec<u32>>(), vec![1]);
+    }
+
+    #[test]
+    fn log_policy_drops_and_counts_items_that_do_not_fit() {
+        let (queue, receiver) = ListenerQueue::channel(&config(OverflowPolicy::Log));
+
+        assert!(queue.offer(1));
+        assert!(!queue.offer(2));
+
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(receiver.try_iter().collect::<Vec<u32>>(), vec![1]);
+    }
+
+    #[test]
+    fn block_policy_keeps_every_item_in_order() {
+        let (queue, receiver) = ListenerQueue::channel(&config(OverflowPolicy::Block));
+
+        let reader = std::thread::spawn(move || {
+            (0..10)
+                .map(|_| {
+                    std::thread::sleep(Duration::from_millis(5));
+                    receiver.recv_timeout(Duration::from_secs(1)).unwrap()
+                })
+                .collect::<Vec<u32>>()
+        });
+        for i in 0..10 {
+            assert!(queue.offer(i));
+        }
+
+        assert_eq!(reader.join().unwrap(), (0..10).collect::<Vec<u32>>());
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn stalled_listener_keeps_queue_capped() {
+        let (release_s, release_r) = crossbeam_channel::unbounded::<()>();
+        let (started_s, started_r) = crossbeam_channel::unbounded();
+        let queue = ListenerQueue::new(
+            &ListenerConfig {
+                queue_size: 4,
+                overflow_policy: OverflowPolicy::Block,
+                block_timeout_ms: 10,
+            },
+            move |_: u32| {
+                started_s.send(()).ok();
+                release_r.recv().ok();
+            },
+        );
+
+        assert!(queue.offer(0));
+        started_r.recv_timeout(Duration::from_secs(1)).unwrap();
+        let offered: Vec<bool> = (1..20).map(|i| queue.offer(i)).collect();
+
+        // the worker holds one item, the queue the next four
+        assert_eq!(offered.iter().filter(|taken| **taken).count(), 4);
+        assert!(queue.sender.len() <= 4);
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 15);
+        drop(release_s);
+    }
+
+    #[test]
+    fn cancelled_subscription_discards_queued_items() {
+        let (release_s, release_r) = crossbeam_channel::unbounded::<()>();
+        let (started_s, started_r) = crossbeam_channel::unbounded();
+        let (handled_s, handled_r) = crossbeam_channel::unbounded();
+        let queue = Arc::new(ListenerQueue::new(
+            &ListenerConfig {
+                queue_size: 10,
+                overflow_policy: OverflowPolicy::Drop,
+                block_timeout_ms: 1000,
+            },
+            move |item: u32| {
+                started_s.send(item).unwrap();
+                release_r.recv().ok();
+                handled_s.send(item).unwrap();
+            },
+        ));
+
+        let listeners = Listeners::new();
+        let subscription = listeners.add(queue.clone()).cancelling(queue.clone());
+        for i in 0..3 {
+            queue.offer(i);
+        }
+        assert_eq!(started_r.recv_timeout(Duration::from_secs(1)), Ok(0));
+
+        subscription.cancel();
+        assert!(listeners.snapshot().is_empty());
+        drop(release_s);
+
+        assert_eq!(handled_r.recv_timeout(Duration::from_secs(1)), Ok(0));
+        assert!(handled_r.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn stream_is_woken_by_offered_items() {
+        for policy in [OverflowPolicy::Drop, OverflowPolicy::Block].iter() {
+            let listeners = Listeners::new();
+            let (queue, receiver) = registered(&listeners, *policy);
+            let mut stream = SubscriptionStream::new(&queue, receiver);
+
+            let queue_ = queue.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                queue_.offer(1);
+            });
+
+            assert_eq!(block_on(stream.next()), Some(1));
+        }
+    }
+
+    #[test]
+    fn dropped_stream_unregisters_queue() {
+        let listeners = Listeners::new();
+        let (queue, receiver) = registered(&listeners, OverflowPolicy::Block);
+        let stream = SubscriptionStream::new(&queue, receiver);
+
+        drop(stream);
+        assert!(listeners.snapshot().is_empty());
+    }
+
+    #[test]
+    fn dropped_receiver_unregisters_queue() {
+        for policy in [OverflowPolicy::Drop, OverflowPolicy::Log].iter() {
+            let listeners = Listeners::new();
+            let (queue, receiver) = registered(&listeners, *policy);
+            drop(receiver);
+
+            assert!(!queue.offer(1));
+            assert!(listeners.snapshot().is_empty());
+        }
+    }
+
+    #[test]
+    fn dropped_receiver_unregisters_blocking_queue() {
+        let listeners = Listeners::new();
+        let (queue, receiver) = registered(&listeners, OverflowPolicy::Block);
+        drop(receiver);
+        queue.offer(1);
+
+        for _ in 0..100 {
+            if listeners.snapshot().is_empty() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("queue is still registered");
+    }
+}
@@ -0,0 +1,246 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::common::Priority;
+use crate::config::LaneConfig;
+
+const LANES: usize = 3;
+/**Bytes a lane may take per share in one round*/
+const QUANTUM: i64 = 1024;
+/**Accounted on top of the payload, so empty items are not free*/
+const ITEM_OVERHEAD: usize = 64;
+
+struct Schedule {
+    current: usize,
+    deficits: [i64; LANES],
+}
+
+/**Queue with a lane per priority class. Lanes are drained by deficit round robin,
+each lane taking its share of the bytes while the others are busy. An idle lane
+leaves its share to the rest. Items pushed to a full lane are dropped, unless they
+are pushed with push_unbounded*/
+pub(crate) struct Lanes<T> {
+    senders: Vec<Sender<(T, usize)>>,
+    receivers: Vec<Receiver<(T, usize)>>,
+    capacity: usize,
+    quanta: [i64; LANES],
+    schedule: Mutex<Schedule>,
+    dropped: AtomicU64,
+}
+
+impl<T> Lanes<T> {
+    pub(crate) fn new(config: &LaneConfig) -> Lanes<T> {
+        let (senders, receivers) = (0..LANES).map(|_| crossbeam_channel::unbounded()).unzip();
+        let share = |share: u32| share.max(1) as i64 * QUANTUM;
+
+        Lanes {
+            senders,
+            receivers,
+            capacity: config.queue_size.max(1),
+            quanta: [
+                share(config.protocol_share),
+                share(config.broadcast_share),
+                share(config.user_share),
+            ],
+            schedule: Mutex::new(Schedule {
+                current: 0,
+                deficits: [0; LANES],
+            }),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn push(
+        &self,
+        priority: Priority,
+        item: T,
+        size: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.senders[priority as usize].len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(Box::from("Lane is full"));
+        }
+
+        self.push_unbounded(priority, item, size);
+        Ok(())
+    }
+
+    /**Queues the item even if its lane is full. For items that must not be lost*/
+    pub(crate) fn push_unbounded(&self, priority: Priority, item: T, size: usize) {
+        // receivers are owned by the lanes, so they never disconnect
+        self.senders[priority as usize]
+            .send((item, size + ITEM_OVERHEAD))
+            .unwrap();
+    }
+
+    /**Items dropped because their lane was full*/
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /**Blocks until any lane has an item*/
+    pub(crate) fn recv(&self) -> T {
+        if let Some(item) = self.try_recv() {
+            return item;
+        }
+
+        // all lanes are empty, so whichever comes first is taken
+        let received = crossbeam_channel::select! {
+            recv(self.receivers[0]) -> item => item,
+            recv(self.receivers[1]) -> item => item,
+            recv(self.receivers[2]) -> item => item,
+        };
+
+        // senders are owned by the lanes, so they never disconnect
+        received.unwrap().0
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        let mut schedule = self.schedule.lock().unwrap();
+
+        for _ in 0..=LANES {
+            let lane = schedule.current;
+            if schedule.deficits[lane] > 0 {
+                match self.receivers[lane].try_recv() {
+                    Ok((item, size)) => {
+                        schedule.deficits[lane] -= size as i64;
+                        return Some(item);
+                    }
+                    Err(_) => schedule.deficits[lane] = 0,
+                }
+            }
+
+            let next = (lane + 1) % LANES;
+            schedule.current = next;
+            if !self.receivers[next].is_empty() {
+                schedule.deficits[next] += self.quanta[next];
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**Items of this size take exactly one quantum*/
+    const SIZE: usize = QUANTUM as usize - ITEM_OVERHEAD;
+
+    fn lanes() -> Lanes<(Priority, usize)> {
+        Lanes::new(&LaneConfig {
+            protocol_share: 3,
+            broadcast_share: 2,
+            user_share: 1,
+            reader_threads: 1,
+            sender_threads: 1,
+            queue_size: 1000,
+            pending_connections: 1,
+        })
+    }
+
+    fn fill(lanes: &Lanes<(Priority, usize)>, priority: Priority, count: usize) {
+        for i in 0..count {
+            lanes.push(priority, (priority, i), SIZE).unwrap();
+        }
+    }
+
+    fn take(lanes: &Lanes<(Priority, usize)>, count: usize) -> Vec<(Priority, usize)> {
+        (0..count).map(|_| lanes.try_recv().unwrap()).collect()
+    }
+
+    fn count(items: &[(Priority, usize)], priority: Priority) -> usize {
+        items.iter().filter(|(p, _)| *p == priority).count()
+    }
+
+    #[test]
+    fn busy_lanes_get_their_shares() {
+        let lanes = lanes();
+        for priority in [Priority::Protocol, Priority::Broadcast, Priority::User].iter() {
+            fill(&lanes, *priority, 100);
+        }
+
+        let items = take(&lanes, 60);
+        assert_eq!(count(&items, Priority::Protocol), 30);
+        assert_eq!(count(&items, Priority::Broadcast), 20);
+        assert_eq!(count(&items, Priority::User), 10);
+    }
+
+    #[test]
+    fn lane_keeps_its_order() {
+        let lanes = lanes();
+        fill(&lanes, Priority::User, 5);
+        fill(&lanes, Priority::Protocol, 5);
+
+        let user: Vec<usize> = take(&lanes, 10)
+            .into_iter()
+            .filter(|(p, _)| *p == Priority::User)
+            .map(|(_, i)| i)
+            .collect();
+        assert_eq!(user, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn idle_lanes_hand_over_their_share() {
+        let lanes = lanes();
+        fill(&lanes, Priority::User, 10);
+
+        // protocol and broadcast lanes are idle, so the user lane takes everything
+        assert_eq!(count(&take(&lanes, 3), Priority::User), 3);
+
+        fill(&lanes, Priority::Protocol, 10);
+        let priorities: Vec<Priority> = take(&lanes, 8).into_iter().map(|(p, _)| p).collect();
+        assert_eq!(
+            priorities,
+            vec![
+                Priority::Protocol,
+                Priority::Protocol,
+                Priority::Protocol,
+                Priority::User,
+                Priority::Protocol,
+                Priority::Protocol,
+                Priority::Protocol,
+                Priority::User,
+            ]
+        );
+    }
+
+    #[test]
+    fn full_lane_drops_and_counts() {
+        let lanes = lanes();
+        fill(&lanes, Priority::User, 1000);
+
+        assert!(lanes
+            .push(Priority::User, (Priority::User, 1000), SIZE)
+            .is_err());
+        assert_eq!(lanes.dropped(), 1);
+
+        // other lanes still take items
+        fill(&lanes, Priority::Protocol, 1);
+        assert_eq!(lanes.dropped(), 1);
+    }
+
+    #[test]
+    fn unbounded_push_goes_past_a_full_lane() {
+        let lanes = lanes();
+        fill(&lanes, Priority::User, 1000);
+
+        lanes.push_unbounded(Priority::User, (Priority::User, 1000), SIZE);
+        assert_eq!(lanes.dropped(), 0);
+        assert_eq!(take(&lanes, 1001).last(), Some(&(Priority::User, 1000)));
+    }
+
+    #[test]
+    fn empty_lanes_return_nothing() {
+        let lanes = lanes();
+        assert!(lanes.try_recv().is_none());
+
+        fill(&lanes, Priority::Broadcast, 1);
+        assert_eq!(lanes.recv(), (Priority::Broadcast, 0));
+        assert!(lanes.try_recv().is_none());
+    }
+}
@@ -1,7 +1,9 @@
+// errors that are only logged are matched with if let Err(_), as everywhere else
+#![allow(clippy::redundant_pattern_matching)]
+
 use std::net::*;
-use std::ops::Deref;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, RwLock};
 
 use broadcast::BroadcastService;
 use common::Address;
@@ -12,9 +14,15 @@ use message::MessagingService;
 use crate::common::{BroadcastMessage, Message, NodeMeta};
 use crate::config::HoverConfig;
 use crate::discovery::DiscoveryProvider;
-use crate::events::{EventListener, EventLoop};
-use crate::message::MessageDispatcher;
-use core::borrow::{Borrow, BorrowMut};
+use crate::events::{ClusterEventListener, EventLoop, Subscription, SubscriptionStream};
+use crate::limits::{DroppedTraffic, Limits};
+use crate::membership::{MembershipEvent, MembershipListener};
+use crate::message::{MessageDispatcher, Request};
+use crate::serialize::CodecKind;
+use crate::transfer::IncomingStream;
+use crossbeam_channel::Receiver;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::error::Error;
 use uuid::Uuid;
 
@@ -22,11 +30,20 @@ pub mod broadcast;
 pub mod common;
 pub mod config;
 pub mod connection;
+pub mod coordinate;
+pub mod detector;
 pub mod discovery;
 pub mod events;
+pub mod lanes;
+pub mod limits;
 pub mod membership;
 pub mod message;
+pub mod query;
+pub mod reliable;
+pub mod retry;
 pub mod serialize;
+pub mod trace;
+pub mod transfer;
 
 /**Main API for using service*/
 pub struct Hover {
@@ -36,7 +53,7 @@ pub struct Hover {
 }
 
 impl Hover {
-    fn new(conf: config::HoverConfig) -> Result<Hover, Box<Error>> {
+    fn new(conf: config::HoverConfig) -> Result<Hover, Box<dyn Error>> {
         println!("Initializing with config: {:?}", conf);
 
         let hover = Hover {
@@ -48,25 +65,23 @@ impl Hover {
         Ok(hover)
     }
 
-    pub fn default() -> Result<Hover, Box<Error>> {
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Result<Hover, Box<dyn Error>> {
         let conf = config::HoverConfig::default()?;
         self::Hover::new(conf)
     }
 
-    pub fn with_conf(conf: config::HoverConfig) -> Result<Hover, Box<Error>> {
+    pub fn with_conf(conf: config::HoverConfig) -> Result<Hover, Box<dyn Error>> {
         self::Hover::new(conf)
     }
 
-    pub fn with_conf_path(path: &str) -> Result<Hover, Box<Error>> {
+    pub fn with_conf_path(path: &str) -> Result<Hover, Box<dyn Error>> {
         let conf = config::HoverConfig::from_file(path)?;
         self::Hover::new(conf)
     }
 
     pub fn get_node_id(&self) -> Option<Uuid> {
-        match self.node {
-            Some(ref node) => Some(node.meta.id.clone()),
-            None => None,
-        }
+        self.node.as_ref().map(|node| node.meta.id)
     }
 
     pub fn get_cluster_service(&self) -> Result<Arc<RwLock<MembershipService>>, &str> {
@@ -76,6 +91,11 @@ impl Hover {
         }
     }
 
+    /**Codec of the message envelopes. Applications may use it for their payloads too*/
+    pub fn get_codec(&self) -> CodecKind {
+        self.config.codec
+    }
+
     pub fn get_messaging_service(&self) -> Result<Arc<RwLock<MessagingService>>, &str> {
         match self.node {
             Some(ref node) => Ok(node.messaging_service.clone()),
@@ -83,6 +103,38 @@ impl Hover {
         }
     }
 
+    /**Traffic refused by the rate limits and the gossip bandwidth cap, and items
+    dropped by full queues: listeners that fell behind, priority lanes and readers*/
+    pub fn get_dropped_traffic(&self) -> Result<DroppedTraffic, &str> {
+        match self.node {
+            Some(ref node) => Ok(DroppedTraffic {
+                listener_items: node.event_loop.dropped_items(),
+                lane_items: node.event_loop.dropped_events()
+                    + node.message_dispatcher.read().unwrap().outbox().dropped(),
+                connections: node
+                    .connection_service
+                    .read()
+                    .unwrap()
+                    .dropped_connections(),
+                ..node.limits.dropped()
+            }),
+            None => Err("Node is not initialized!"),
+        }
+    }
+
+    /**Messages received from nodes configured with another codec. They are still read,
+    but all nodes of a cluster should use the same codec*/
+    pub fn get_foreign_codec_messages(&self) -> Result<u64, &str> {
+        match self.node {
+            Some(ref node) => Ok(node
+                .connection_service
+                .read()
+                .unwrap()
+                .foreign_codec_messages()),
+            None => Err("Node is not initialized!"),
+        }
+    }
+
     pub fn start(&mut self) -> Result<(), &str> {
         match self.started {
             true => Err("Hover is already started!"),
@@ -96,41 +148,172 @@ impl Hover {
         }
     }
 
-    pub fn add_msg_listener<F>(&mut self, f: F) -> Result<&Hover, Box<()>>
+    /**Registers a listener of incoming requests. It stays registered
+    until the returned subscription is dropped or cancelled*/
+    pub fn add_msg_listener<F>(&mut self, f: F) -> Result<Subscription, Box<()>>
     where
-        F: Fn(Arc<Message>) -> () + 'static + Send + Sync,
+        F: Fn(Arc<Message>) + 'static + Send + Sync,
     {
         match self.node {
-            Some(ref mut n) => match n.add_msg_listener(f) {
-                Ok(_) => Ok(self),
-                Err(_) => Err(Box::new(())),
-            },
+            Some(ref mut n) => n.add_msg_listener(f),
             None => Err(Box::new(())),
         }
     }
 
-    pub fn add_broadcast_listener<F>(&self, f: F) -> Result<&Hover, Box<()>>
+    /**Sets the handler that answers requests. See MessageDispatcher::set_request_handler*/
+    pub fn set_request_handler<F>(&self, f: F) -> Result<Subscription, Box<()>>
     where
-        F: Fn(Arc<BroadcastMessage>) -> () + 'static + Send + Sync,
+        F: Fn(Request) + 'static + Send + Sync,
     {
         match self.node {
-            Some(ref n) => match n.add_broadcast_listener(f) {
-                Ok(_) => Ok(self),
-                Err(_) => Err(Box::new(())),
-            },
+            Some(ref node) => Ok(node
+                .message_dispatcher
+                .read()
+                .unwrap()
+                .set_request_handler(f)),
             None => Err(Box::new(())),
         }
     }
 
-    pub fn add_event_listener<T>(&self, listener: T) -> Result<&Hover, Box<()>>
+    /**Sets the handler of incoming streams. See MessageDispatcher::set_stream_handler*/
+    pub fn set_stream_handler<F>(&self, f: F) -> Result<Subscription, Box<()>>
     where
-        T: EventListener + Send + Sync + 'static,
+        F: Fn(IncomingStream) + 'static + Send + Sync,
     {
         match self.node {
-            Some(ref node) => match node.add_event_listener(listener) {
-                Ok(_) => Ok(self),
-                Err(_) => Err(Box::new(())),
-            },
+            Some(ref node) => Ok(node
+                .message_dispatcher
+                .read()
+                .unwrap()
+                .set_stream_handler(f)),
+            None => Err(Box::new(())),
+        }
+    }
+
+    /**Registers a typed handler of the method, e.g. "service.method".
+    See MessagingService::call for the calling side*/
+    pub fn register_handler<Req, Resp, F>(
+        &self,
+        method: &str,
+        f: F,
+    ) -> Result<Subscription, Box<()>>
+    where
+        F: Fn(Req) -> Result<Resp, Box<dyn Error>> + 'static + Send + Sync,
+        Req: DeserializeOwned,
+        Resp: Serialize,
+    {
+        match self.node {
+            Some(ref node) => Ok(node
+                .message_dispatcher
+                .read()
+                .unwrap()
+                .register_handler(method, f)),
+            None => Err(Box::new(())),
+        }
+    }
+
+    pub fn add_broadcast_listener<F>(&self, f: F) -> Result<Subscription, Box<()>>
+    where
+        F: Fn(Arc<BroadcastMessage>) + 'static + Send + Sync,
+    {
+        match self.node {
+            Some(ref n) => n.add_broadcast_listener(f),
+            None => Err(Box::new(())),
+        }
+    }
+
+    /**Registers typed membership callbacks. See MembershipService::add_membership_listener*/
+    pub fn add_membership_listener<T>(
+        &self,
+        listener: T,
+        replay: bool,
+    ) -> Result<Subscription, Box<()>>
+    where
+        T: MembershipListener + Send + Sync + 'static,
+    {
+        match self.node {
+            Some(ref node) => node.add_membership_listener(listener, replay),
+            None => Err(Box::new(())),
+        }
+    }
+
+    /**Registers a listener of cluster events. Protocol internals are not exposed*/
+    pub fn add_event_listener<T>(&self, listener: T) -> Result<Subscription, Box<()>>
+    where
+        T: ClusterEventListener + Send + Sync + 'static,
+    {
+        match self.node {
+            Some(ref node) => node.add_event_listener(listener),
+            None => Err(Box::new(())),
+        }
+    }
+}
+
+/**Channel based subscriptions. Consumers decide on which thread the items are handled*/
+impl Hover {
+    pub fn subscribe_messages(&self) -> Result<Receiver<Arc<Message>>, Box<()>> {
+        match self.node {
+            Some(ref node) => Ok(node.message_dispatcher.read().unwrap().subscribe_messages()),
+            None => Err(Box::new(())),
+        }
+    }
+
+    pub fn subscribe_broadcasts(&self) -> Result<Receiver<Arc<BroadcastMessage>>, Box<()>> {
+        match self.node {
+            Some(ref node) => Ok(node
+                .broadcast_service
+                .read()
+                .unwrap()
+                .subscribe_broadcasts()),
+            None => Err(Box::new(())),
+        }
+    }
+
+    pub fn subscribe_membership(&self, replay: bool) -> Result<Receiver<MembershipEvent>, Box<()>> {
+        match self.node {
+            Some(ref node) => Ok(node
+                .membership_service
+                .read()
+                .unwrap()
+                .subscribe_membership(replay)),
+            None => Err(Box::new(())),
+        }
+    }
+
+    pub fn subscribe_messages_stream(&self) -> Result<SubscriptionStream<Arc<Message>>, Box<()>> {
+        match self.node {
+            Some(ref node) => Ok(node
+                .message_dispatcher
+                .read()
+                .unwrap()
+                .subscribe_messages_stream()),
+            None => Err(Box::new(())),
+        }
+    }
+
+    pub fn subscribe_broadcasts_stream(
+        &self,
+    ) -> Result<SubscriptionStream<Arc<BroadcastMessage>>, Box<()>> {
+        match self.node {
+            Some(ref node) => Ok(node
+                .broadcast_service
+                .read()
+                .unwrap()
+                .subscribe_broadcasts_stream()),
+            None => Err(Box::new(())),
+        }
+    }
+
+    pub fn subscribe_membership_stream(
+        &self,
+        replay: bool,
+    ) -> Result<SubscriptionStream<MembershipEvent>, Box<()>> {
+        match self.node {
+            Some(ref node) => Ok(node
+                .membership_service
+                .read()
+                .unwrap()
+                .subscribe_membership_stream(replay)),
             None => Err(Box::new(())),
         }
     }
@@ -139,14 +322,14 @@ impl Hover {
 /**Representation of the Hover node*/
 struct Node {
     meta: NodeMeta,
-    config: HoverConfig,
     connection_service: Arc<RwLock<ConnectionService>>,
     broadcast_service: Arc<RwLock<BroadcastService>>,
     messaging_service: Arc<RwLock<MessagingService>>,
     membership_service: Arc<RwLock<MembershipService>>,
     message_dispatcher: Arc<RwLock<MessageDispatcher>>,
     discovery_provider: Arc<RwLock<DiscoveryProvider>>,
-    event_loop: Arc<RwLock<EventLoop>>,
+    limits: Arc<Limits>,
+    event_loop: Arc<EventLoop>,
 }
 
 impl Node {
@@ -161,23 +344,41 @@ impl Node {
             },
         };
 
-        /**Get multicast configs from config object*/
+        //get multicast configs from config object
         let multicast_addr = Address {
             ip: Ipv4Addr::from_str(conf.discovery.multicast_group.as_str()).unwrap(),
             port: conf.discovery.multicast_port,
         };
 
-        let event_loop = Arc::new(RwLock::new(EventLoop::new()));
+        let event_loop = Arc::new(EventLoop::new(
+            conf.listeners.clone(),
+            conf.subscriptions.clone(),
+            conf.lanes.clone(),
+        ));
+
+        let limits = Arc::new(Limits::new(conf.limits.clone()));
 
         let connection_service = Arc::new(RwLock::new(ConnectionService::new(
             node_meta.clone(),
+            conf.lanes.clone(),
+            conf.codec,
+            limits.clone(),
             event_loop.clone(),
         )));
 
-        let message_dispatcher = Arc::new(RwLock::new(MessageDispatcher::new(event_loop.clone())));
+        let message_dispatcher = Arc::new(RwLock::new(MessageDispatcher::new(
+            node_meta.clone(),
+            conf.codec,
+            conf.streams.clone(),
+            conf.lanes.clone(),
+            event_loop.clone(),
+        )));
 
         let messaging_service = Arc::new(RwLock::new(MessagingService::new(
             node_meta.clone(),
+            conf.codec,
+            conf.messaging.clone(),
+            conf.streams.clone(),
             message_dispatcher.clone(),
             event_loop.clone(),
         )));
@@ -189,6 +390,11 @@ impl Node {
             event_loop.clone(),
         )));
 
+        messaging_service
+            .read()
+            .unwrap()
+            .bind_membership_service(&membership_service);
+
         let discovery_provider = Arc::new(RwLock::new(DiscoveryProvider::new(
             node_meta.clone(),
             conf.discovery.clone(),
@@ -200,80 +406,77 @@ impl Node {
             node_meta.clone(),
             conf.broadcast.clone(),
             multicast_addr,
+            conf.codec,
             membership_service.clone(),
             messaging_service.clone(),
+            limits.clone(),
             event_loop.clone(),
         )));
 
-        event_loop
-            .write()
-            .unwrap()
-            .add_listener(membership_service.clone())
-            .unwrap()
-            .add_listener(message_dispatcher.clone())
-            .unwrap()
-            .add_listener(broadcast_service.clone())
-            .unwrap()
-            .add_listener(discovery_provider.clone())
-            .unwrap();
+        event_loop.add_listener(membership_service.clone()).detach();
+        event_loop.add_listener(message_dispatcher.clone()).detach();
+        event_loop.add_listener(broadcast_service.clone()).detach();
 
         Node {
             meta: node_meta.clone(),
-            config: conf,
             connection_service,
             broadcast_service,
             messaging_service,
             membership_service,
             message_dispatcher,
             discovery_provider,
+            limits,
             event_loop,
         }
     }
 
     fn start(&self) {
-        self.event_loop.read().unwrap().start();
+        self.event_loop.start();
 
         self.connection_service.read().unwrap().start();
-        self.broadcast_service.read().unwrap().start();
+        if let Err(err) = self.broadcast_service.read().unwrap().start() {
+            eprintln!("[Node]: Failed to start the broadcast service: {}", err);
+        }
         self.discovery_provider.read().unwrap().start();
         self.membership_service.read().unwrap().start();
 
         println!("[Node]: Started");
     }
 
-    fn add_msg_listener<F>(&mut self, f: F) -> Result<(), Box<()>>
+    fn add_msg_listener<F>(&mut self, f: F) -> Result<Subscription, Box<()>>
     where
-        F: Fn(Arc<Message>) -> () + 'static + Send + Sync,
+        F: Fn(Arc<Message>) + 'static + Send + Sync,
     {
-        match self.message_dispatcher.write().unwrap().add_msg_listener(f) {
-            Ok(_) => Ok(()),
+        match self.message_dispatcher.read() {
+            Ok(md) => Ok(md.add_msg_listener(f)),
             Err(_) => Err(Box::new(())),
         }
     }
 
-    fn add_broadcast_listener<F>(&self, f: F) -> Result<(), Box<()>>
+    fn add_broadcast_listener<F>(&self, f: F) -> Result<Subscription, Box<()>>
     where
-        F: Fn(Arc<BroadcastMessage>) -> () + 'static + Send + Sync,
+        F: Fn(Arc<BroadcastMessage>) + 'static + Send + Sync,
     {
-        match self
-            .broadcast_service
-            .write()
-            .unwrap()
-            .add_broadcast_listener(f)
-        {
-            Ok(_) => Ok(()),
+        match self.broadcast_service.read() {
+            Ok(bs) => Ok(bs.add_broadcast_listener(f)),
             Err(_) => Err(Box::new(())),
         }
     }
 
-    fn add_event_listener<T>(&self, listener: T) -> Result<(), Box<()>>
+    fn add_membership_listener<T>(&self, listener: T, replay: bool) -> Result<Subscription, Box<()>>
     where
-        T: EventListener + Send + Sync + 'static,
+        T: MembershipListener + Send + Sync + 'static,
     {
-        let lis = Arc::new(RwLock::new(listener));
-        match self.event_loop.read() {
-            Ok(l) => l.add_listener(lis).map(|_| ()).map_err(|_| Box::new(())),
+        match self.membership_service.read() {
+            Ok(ms) => Ok(ms.add_membership_listener(listener, replay)),
             Err(_) => Err(Box::new(())),
         }
     }
+
+    fn add_event_listener<T>(&self, listener: T) -> Result<Subscription, Box<()>>
+    where
+        T: ClusterEventListener + Send + Sync + 'static,
+    {
+        Ok(self.event_loop.add_user_listener(listener))
+    }
 }
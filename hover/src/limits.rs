@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::common::Priority;
+use crate::config::LimitConfig;
+
+/**Sources are forgotten after being idle this long, once there are many of them*/
+const SOURCE_IDLE: Duration = Duration::from_secs(60);
+const MAX_SOURCES: usize = 4096;
+
+/**Traffic refused by the limits since the node started*/
+#[derive(Debug, Clone, Default)]
+pub struct DroppedTraffic {
+    /**Incoming messages over the message or byte rate of their source*/
+    pub inbound_messages: u64,
+    pub inbound_bytes: u64,
+    /**Incoming messages over the maximum message size*/
+    pub oversized_messages: u64,
+    /**Gossip sends put off to a later round by the bandwidth cap*/
+    pub deferred_gossip: u64,
+    /**Items dropped by listeners and subscriptions that fell behind*/
+    pub listener_items: u64,
+    /**Incoming and outgoing messages dropped by full priority lanes*/
+    pub lane_items: u64,
+    /**Connections closed unread because all reader threads were busy*/
+    pub connections: u64,
+}
+
+/**Token bucket. A take is allowed while the bucket holds a whole token and may
+leave it in debt, so items larger than the burst still pass but hold up the next ones.
+A rate of zero disables the bucket*/
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: u64) -> TokenBucket {
+        let capacity = burst.max(rate) as f64;
+        TokenBucket {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+    }
+
+    fn is_available(&mut self) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self, amount: u64) -> bool {
+        if !self.is_available() {
+            return false;
+        }
+        self.tokens -= amount as f64;
+        true
+    }
+}
+
+struct SourceBuckets {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+/**Rate limits of the node. Incoming traffic is limited per source address, so a
+noisy peer does not starve the others. Nodes sharing a host share the limit.
+Outgoing gossip is limited by a single bandwidth cap*/
+pub(crate) struct Limits {
+    config: LimitConfig,
+    sources: Mutex<HashMap<IpAddr, SourceBuckets>>,
+    gossip: Mutex<TokenBucket>,
+    inbound_messages: AtomicU64,
+    inbound_bytes: AtomicU64,
+    oversized_messages: AtomicU64,
+    deferred_gossip: AtomicU64,
+}
+
+impl Limits {
+    pub(crate) fn new(config: LimitConfig) -> Limits {
+        let gossip = TokenBucket::new(config.gossip_bytes_per_sec, config.gossip_byte_burst);
+
+        Limits {
+            config,
+            sources: Mutex::new(HashMap::new()),
+            gossip: Mutex::new(gossip),
+            inbound_messages: AtomicU64::new(0),
+            inbound_bytes: AtomicU64::new(0),
+            oversized_messages: AtomicU64::new(0),
+            deferred_gossip: AtomicU64::new(0),
+        }
+    }
+
+    /**Incoming messages are read up to this size. One byte over the maximum tells
+    an oversized message apart*/
+    pub(crate) fn read_limit(&self) -> u64 {
+        match self.config.max_message_bytes {
+            0 => u64::MAX,
+            max => max.saturating_add(1),
+        }
+    }
+
+    /**Checked once the message is decoded. Protocol messages are always admitted,
+    so a flood of other traffic does not make the source look dead*/
+    pub(crate) fn admit_message(&self, source: IpAddr, priority: Priority) -> bool {
+        if let Priority::Protocol = priority {
+            return true;
+        }
+
+        let admitted = self.with_source(source, |buckets| buckets.messages.take(1));
+        if !admitted {
+            self.inbound_messages.fetch_add(1, Ordering::Relaxed);
+        }
+        admitted
+    }
+
+    /**Checked after the message is read, before it is posted to the event loop*/
+    pub(crate) fn admit_bytes(&self, source: IpAddr, size: usize) -> bool {
+        let max = self.config.max_message_bytes;
+        if max > 0 && size as u64 > max {
+            self.oversized_messages.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        let admitted = self.with_source(source, |buckets| buckets.bytes.take(size as u64));
+        if !admitted {
+            self.inbound_messages.fetch_add(1, Ordering::Relaxed);
+            self.inbound_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        }
+        admitted
+    }
+
+    /**False while the gossip cap is used up. The send is then put off*/
+    pub(crate) fn gossip_available(&self) -> bool {
+        let available = self.gossip.lock().unwrap().is_available();
+        if !available {
+            self.deferred_gossip.fetch_add(1, Ordering::Relaxed);
+        }
+        available
+    }
+
+    pub(crate) fn gossip_sent(&self, size: usize) {
+        self.gossip.lock().unwrap().take(size as u64);
+    }
+
+    pub(crate) fn dropped(&self) -> DroppedTraffic {
+        DroppedTraffic {
+            inbound_messages: self.inbound_messages.load(Ordering::Relaxed),
+            inbound_bytes: self.inbound_bytes.load(Ordering::Relaxed),
+            oversized_messages: self.oversized_messages.load(Ordering::Relaxed),
+            deferred_gossip: self.deferred_gossip.load(Ordering::Relaxed),
+            ..DroppedTraffic::default()
+        }
+    }
+
+    fn with_source<F>(&self, source: IpAddr, f: F) -> bool
+    where
+        F: FnOnce(&mut SourceBuckets) -> bool,
+    {
+        let mut sources = self.sources.lock().unwrap();
+
+        if sources.len() >= MAX_SOURCES && !sources.contains_key(&source) {
+            sources.retain(|_, buckets| buckets.messages.last.elapsed() < SOURCE_IDLE);
+        }
+
+        let config = &self.config;
+        let buckets = sources.entry(source).or_insert_with(|| SourceBuckets {
+            messages: TokenBucket::new(
+                config.inbound_messages_per_sec,
+                config.inbound_message_burst,
+            ),
+            bytes: TokenBucket::new(config.inbound_bytes_per_sec, config.inbound_byte_burst),
+        });
+
+        f(buckets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn config(max_message_bytes: u64) -> LimitConfig {
+        LimitConfig {
+            inbound_messages_per_sec: 1,
+            inbound_message_burst: 2,
+            inbound_bytes_per_sec: 100,
+            inbound_byte_burst: 100,
+            max_message_bytes,
+            gossip_bytes_per_sec: 100,
+            gossip_byte_burst: 100,
+        }
+    }
+
+    fn source(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refuses() {
+        let mut bucket = TokenBucket::new(1, 3);
+
+        assert!((0..3).all(|_| bucket.take(1)));
+        assert!(!bucket.take(1));
+    }
+
+    #[test]
+    fn bucket_lets_large_item_through_into_debt() {
+        let mut bucket = TokenBucket::new(100, 100);
+
+        assert!(bucket.take(250));
+        assert!(!bucket.is_available());
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut bucket = TokenBucket::new(100, 1);
+        assert!(bucket.take(100));
+        assert!(!bucket.take(1));
+
+        bucket.last -= Duration::from_millis(50);
+        assert!(bucket.take(1));
+    }
+
+    #[test]
+    fn zero_rate_disables_bucket() {
+        let mut bucket = TokenBucket::new(0, 0);
+        assert!((0..1000).all(|_| bucket.take(u64::MAX)));
+    }
+
+    #[test]
+    fn sources_are_limited_separately() {
+        let limits = Limits::new(config(1000));
+
+        assert!(limits.admit_message(source(1), Priority::User));
+        assert!(limits.admit_message(source(1), Priority::Broadcast));
+        assert!(!limits.admit_message(source(1), Priority::User));
+        assert!(limits.admit_message(source(2), Priority::User));
+
+        // probes and acks are never refused
+        assert!(limits.admit_message(source(1), Priority::Protocol));
+        assert_eq!(limits.dropped().inbound_messages, 1);
+    }
+
+    #[test]
+    fn oversized_messages_are_dropped() {
+        let limits = Limits::new(config(10));
+        assert_eq!(limits.read_limit(), 11);
+
+        assert!(limits.admit_bytes(source(1), 10));
+        assert!(!limits.admit_bytes(source(1), 11));
+        assert_eq!(limits.dropped().oversized_messages, 1);
+    }
+
+    #[test]
+    fn zero_max_message_bytes_disables_limit() {
+        let limits = Limits::new(config(0));
+        assert_eq!(limits.read_limit(), u64::MAX);
+
+        assert!(limits.admit_bytes(source(1), 50));
+        assert_eq!(limits.dropped().oversized_messages, 0);
+    }
+
+    #[test]
+    fn gossip_is_deferred_over_cap() {
+        let limits = Limits::new(config(1000));
+
+        assert!(limits.gossip_available());
+        limits.gossip_sent(200);
+        assert!(!limits.gossip_available());
+        assert_eq!(limits.dropped().deferred_gossip, 1);
+    }
+}
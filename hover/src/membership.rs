@@ -1,52 +1,90 @@
 extern crate rand;
 extern crate socket2;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use self::rand::seq::SliceRandom;
-use crate::common::{Address, Message, MessageType, NodeMeta, ProbeReqPayload};
-use crate::events::Event::{MemberAdded, MemberLeft};
-use crate::events::{Event, EventListener, EventLoop};
+use self::rand::Rng;
+use crate::common::{
+    AckPayload, Address, Member, MemberStatus, MembershipUpdate, Message, MessageType, NodeMeta,
+    ProbePayload, ProbeReqPayload,
+};
+use crate::events::Event::{
+    MemberAdded, MemberLeft, MemberSuspected, MemberUpdated, PartitionHealed, PartitionSuspected,
+};
+use crate::events::{
+    Event, EventListener, EventLoop, ListenerQueue, Listeners, Subscription, SubscriptionStream,
+};
 use crate::message::MessagingService;
 use crate::serialize;
+use crate::trace::{self, TraceContext};
 
 use crate::config::DiscoveryConfig;
-use chashmap::CHashMap;
-use core::borrow::Borrow;
+use crate::coordinate::{Coordinate, CoordinateClient};
+use crate::detector::{self, FailureDetector};
+use crossbeam_channel::Receiver;
 use std::error::Error;
-use std::net::{IpAddr, Ipv4Addr};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use uuid::Uuid;
 
+/**Max number of membership updates piggybacked onto a single message*/
+const MAX_PIGGYBACKED_UPDATES: usize = 16;
+
+/**Typed callbacks on membership changes. Every method has an empty default
+implementation, so only the needed ones can be overridden*/
+pub trait MembershipListener {
+    fn on_join(&self, _member: &Member) {}
+    fn on_leave(&self, _member: &Member) {}
+    fn on_update(&self, _member: &Member) {}
+    fn on_suspect(&self, _member: &Member) {}
+}
+
+/**Membership change delivered through a subscription channel*/
+#[derive(Clone, Debug)]
+pub enum MembershipEvent {
+    Joined(Member),
+    Left(Member),
+    Updated(Member),
+    Suspected(Member),
+}
+
 /**Service that allows to retrieve info about cluster members*/
 pub struct MembershipService {
     local_node_meta: NodeMeta,
     messaging_service: Arc<RwLock<MessagingService>>,
+    event_loop: Arc<EventLoop>,
     swim: Arc<SwimProtocol>,
     swim_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    listeners: Listeners<Arc<ListenerQueue<MembershipEvent>>>,
+    // held while changes are handed to listeners and while a listener is replayed
+    // and registered, so no change falls in between
+    notify_lock: Mutex<()>,
 }
 
 impl MembershipService {
-    pub fn new(
+    pub(crate) fn new(
         local_node_meta: NodeMeta,
         config: DiscoveryConfig,
         messaging_service: Arc<RwLock<MessagingService>>,
-        event_loop: Arc<RwLock<EventLoop>>,
+        event_loop: Arc<EventLoop>,
     ) -> MembershipService {
         let swim = SwimProtocol::new(
             local_node_meta.clone(),
             config,
             messaging_service.clone(),
-            event_loop,
+            event_loop.clone(),
         );
 
         MembershipService {
             local_node_meta,
             messaging_service,
+            event_loop,
             swim: Arc::new(swim),
             swim_thread: Arc::new(Mutex::new(None)),
+            listeners: Listeners::new(),
+            notify_lock: Mutex::new(()),
         }
     }
 
@@ -64,72 +102,284 @@ impl MembershipService {
 
     /**Returns the full copy of the current members state*/
     pub fn get_members(&self) -> Vec<NodeMeta> {
-        self.swim.members.clone().read().unwrap().clone()
+        self.swim.members()
     }
 
     pub fn get_member_by_id(&self, member_id: &Uuid) -> Option<NodeMeta> {
         self.swim
-            .members
+            .states
             .read()
             .unwrap()
-            .iter()
-            .find(|n| n.id == *member_id)
-            .map(|n| n.clone())
+            .get(member_id)
+            .map(|state| state.node.clone())
+    }
+
+    /**Node of the member if it is alive. Suspected members are not returned*/
+    pub(crate) fn alive_member(&self, member_id: &Uuid) -> Option<NodeMeta> {
+        self.swim
+            .states
+            .read()
+            .unwrap()
+            .get(member_id)
+            .filter(|state| state.status == MemberStatus::Alive)
+            .map(|state| state.node.clone())
     }
 
     pub fn get_member_by_address(&self, address: &Address) -> Option<NodeMeta> {
         self.swim
-            .members
+            .states
             .read()
             .unwrap()
-            .iter()
-            .find(|n| n.addr == *address)
-            .map(|n| n.clone())
+            .values()
+            .find(|state| state.node.addr == *address)
+            .map(|state| state.node.clone())
     }
 
     pub fn get_member_count(&self) -> usize {
-        self.swim.members.read().unwrap().len()
+        self.swim.states.read().unwrap().len()
+    }
+
+    /**Registers a membership listener. If replay is set, the listener is notified
+    with on_join about every current member before any later change. A change made
+    right before the registration may be received after the replay as well*/
+    pub fn add_membership_listener<T>(&self, listener: T, replay: bool) -> Subscription
+    where
+        T: MembershipListener + Send + Sync + 'static,
+    {
+        let queue = self.event_loop.listener_queue(move |change| match change {
+            MembershipEvent::Joined(m) => listener.on_join(&m),
+            MembershipEvent::Left(m) => listener.on_leave(&m),
+            MembershipEvent::Updated(m) => listener.on_update(&m),
+            MembershipEvent::Suspected(m) => listener.on_suspect(&m),
+        });
+
+        let _notify = self.notify_lock.lock().unwrap();
+        if replay {
+            self.replay_members(&queue);
+        }
+
+        self.listeners.add(queue.clone()).cancelling(queue)
+    }
+
+    /**Channel of membership changes. It is unregistered once the receiver is dropped.
+    If replay is set, every current member is received as joined first*/
+    pub fn subscribe_membership(&self, replay: bool) -> Receiver<MembershipEvent> {
+        let (_, receiver) = self.subscribe_queue(replay);
+        receiver
+    }
+
+    /**Async variant of subscribe_membership*/
+    pub fn subscribe_membership_stream(&self, replay: bool) -> SubscriptionStream<MembershipEvent> {
+        let (queue, receiver) = self.subscribe_queue(replay);
+        SubscriptionStream::new(&queue, receiver)
+    }
+
+    fn subscribe_queue(
+        &self,
+        replay: bool,
+    ) -> (
+        Arc<ListenerQueue<MembershipEvent>>,
+        Receiver<MembershipEvent>,
+    ) {
+        let (queue, receiver) = self.event_loop.channel_queue();
+
+        let _notify = self.notify_lock.lock().unwrap();
+        if replay {
+            self.replay_members(&queue);
+        }
+
+        self.event_loop
+            .register_channel(&self.listeners, queue.clone());
+        (queue, receiver)
+    }
+
+    fn replay_members(&self, queue: &Arc<ListenerQueue<MembershipEvent>>) {
+        let event_loop = &self.event_loop;
+        for member in self.all_members() {
+            match member.status {
+                MemberStatus::Alive => {
+                    event_loop.dispatch(queue, MembershipEvent::Joined(member));
+                }
+                MemberStatus::Suspect => {
+                    event_loop.dispatch(queue, MembershipEvent::Joined(member.clone()));
+                    event_loop.dispatch(queue, MembershipEvent::Suspected(member));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /**Returns alive, suspected and recently removed members*/
+    pub fn all_members(&self) -> Vec<Member> {
+        self.swim.all_members()
+    }
+
+    pub fn member(&self, member_id: &Uuid) -> Option<Member> {
+        self.swim
+            .all_members()
+            .into_iter()
+            .find(|m| m.node.id == *member_id)
+    }
+
+    pub fn members_with_status(&self, status: MemberStatus) -> Vec<Member> {
+        self.swim
+            .all_members()
+            .into_iter()
+            .filter(|m| m.status == status)
+            .collect()
+    }
+
+    pub fn local_member(&self) -> Member {
+        self.swim.local_member()
+    }
+
+    /**Returns the network coordinate of the local node or an alive member*/
+    pub fn coordinate(&self, member_id: &Uuid) -> Option<Coordinate> {
+        self.swim.coordinate(member_id)
+    }
+
+    /**Estimates RTT between two nodes from their network coordinates*/
+    pub fn estimate_rtt(&self, a: &Uuid, b: &Uuid) -> Option<Duration> {
+        match (self.swim.coordinate(a), self.swim.coordinate(b)) {
+            (Some(a), Some(b)) => Some(a.distance_to(&b)),
+            _ => None,
+        }
     }
 
-    fn handle_joined_node(&self, node: NodeMeta) {
+    /**Returns up to n alive members with the lowest estimated RTT to the local node*/
+    pub fn nearest_members(&self, n: usize) -> Vec<Member> {
+        let local = self.swim.coordinate(&self.local_node_meta.id).unwrap();
+
+        let members = self
+            .members_with_status(MemberStatus::Alive)
+            .into_iter()
+            .filter_map(|m| self.swim.coordinate(&m.node.id).map(|c| (m, c)))
+            .collect();
+
+        nearest(&local, members, n)
+    }
+
+    /**Returns true if the local side of the cluster holds the majority of expected_size*/
+    pub fn has_quorum(&self, expected_size: usize) -> bool {
+        has_quorum(self.get_member_count() + 1, expected_size)
+    }
+
+    /**Returns true if a large fraction of members has recently disappeared*/
+    pub fn is_partition_suspected(&self) -> bool {
+        self.swim.partition.lock().unwrap().is_suspected()
+    }
+
+    /**Replaces tags of the local node and disseminates them across the cluster*/
+    pub fn set_tags(&self, tags: HashMap<String, String>) {
+        self.swim.set_local_tags(tags);
+    }
+
+    /**Returns the current alive update of the local node*/
+    pub fn local_update(&self) -> MembershipUpdate {
+        self.swim.local_update()
+    }
+
+    /**Evicts a member and disseminates that it has left. The id is not re-admitted,
+    whatever incarnation it comes back with, until it is reaped with the tombstones
+    after `tombstone_reap_ms`. A restarted node joins under a new id*/
+    pub fn force_leave(&self, member_id: &Uuid) -> Result<(), &str> {
+        match self.swim.force_leave(member_id) {
+            true => Ok(()),
+            false => Err("Unknown member!"),
+        }
+    }
+
+    /**Takes the next portion of membership updates to piggyback onto a message*/
+    pub(crate) fn take_updates(&self) -> Vec<MembershipUpdate> {
+        self.swim.take_updates()
+    }
+
+    fn handle_joined_node(&self, node: NodeMeta, incarnation: u64, tags: HashMap<String, String>) {
         println!("[MembershipService]: Handled joined node: {:?}", node);
-        self.swim.add_member(node);
+        self.swim.add_member(MembershipUpdate {
+            from: node.id,
+            node,
+            status: MemberStatus::Alive,
+            incarnation,
+            tags,
+        });
+    }
+
+    fn notify_listeners(&self, change: MembershipEvent) {
+        let _notify = self.notify_lock.lock().unwrap();
+
+        let event_loop = &self.event_loop;
+        for listener in self.listeners.snapshot().iter() {
+            event_loop.dispatch(listener, change.clone());
+        }
     }
 
     fn handle_left_node(&self, node: NodeMeta) {
         println!("[MembershipService]: Handled left node: {:?}", node);
-        self.swim.remove_member(node);
+        self.swim.remove_member(node, MemberStatus::Left);
     }
 
     fn handle_probe(&self, cor_id: Uuid, return_addr: Address) {
-        self.messaging_service
-            .read()
-            .unwrap()
-            .reply(cor_id, Vec::new(), return_addr);
+        let result = self.messaging_service.read().unwrap().reply(
+            cor_id,
+            self.swim.build_ack_payload(false),
+            return_addr,
+        );
+        if let Err(_) = result {
+            eprintln!("[MembershipService]: Error while sending ack!");
+        }
     }
 
+    /**Replies with a nack if the requested node did not answer, so the requester
+    knows that it is not the one who is slow. The probe waits for its timeout, so it
+    runs on its own thread instead of the event loop*/
     fn handle_probe_req(&self, cor_id: Uuid, probe_node: NodeMeta, return_addr: Address) {
-        match self.swim.probe_member(&probe_node) {
-            Ok(_) => {
-                self.messaging_service
-                    .read()
-                    .unwrap()
-                    .reply(cor_id, Vec::new(), return_addr);
+        let swim_ = self.swim.clone();
+        let messaging_ = self.messaging_service.clone();
+
+        std::thread::spawn(move || {
+            let nack = swim_.probe_member(&probe_node).is_err();
+
+            let result = messaging_.read().unwrap().reply(
+                cor_id,
+                swim_.build_ack_payload(nack),
+                return_addr,
+            );
+            if let Err(_) = result {
+                eprintln!("[MembershipService]: Error while sending ack of a probe request!");
             }
-            _ => {}
-        }
+        });
     }
 }
 
 impl EventListener for MembershipService {
     fn on_event(&self, event: Event) {
         match event {
-            Event::JoinIn { node_meta } => {
-                self.handle_joined_node(node_meta);
+            Event::JoinIn {
+                node_meta,
+                incarnation,
+                tags,
+            } => {
+                self.handle_joined_node(node_meta, incarnation, tags);
             }
             Event::LeftIn { node_meta } => {
                 self.handle_left_node(node_meta);
             }
+            Event::MembershipUpdatesIn { updates } => {
+                self.swim.apply_updates(updates);
+            }
+            Event::MemberAdded { member } => {
+                self.notify_listeners(MembershipEvent::Joined(member));
+            }
+            Event::MemberLeft { member } => {
+                self.notify_listeners(MembershipEvent::Left(member));
+            }
+            Event::MemberUpdated { member } => {
+                self.notify_listeners(MembershipEvent::Updated(member));
+            }
+            Event::MemberSuspected { member } => {
+                self.notify_listeners(MembershipEvent::Suspected(member));
+            }
             Event::ProbeIn {
                 cor_id,
                 return_address,
@@ -148,14 +398,291 @@ impl EventListener for MembershipService {
     }
 }
 
+/**Local view of a single member*/
+#[derive(Debug, Clone)]
+struct MemberState {
+    node: NodeMeta,
+    status: MemberStatus,
+    incarnation: u64,
+    tags: HashMap<String, String>,
+    suspicion: Option<Suspicion>,
+    joined_at: SystemTime,
+    last_probed_at: Option<SystemTime>,
+    last_rtt: Option<Duration>,
+    coordinate: Option<Coordinate>,
+}
+
+impl MemberState {
+    fn to_member(&self) -> Member {
+        Member {
+            node: self.node.clone(),
+            status: self.status,
+            incarnation: self.incarnation,
+            tags: self.tags.clone(),
+            joined_at: self.joined_at,
+            last_probed_at: self.last_probed_at,
+            last_rtt: self.last_rtt,
+        }
+    }
+
+    fn to_update(&self, from: Uuid) -> MembershipUpdate {
+        MembershipUpdate {
+            node: self.node.clone(),
+            status: self.status,
+            incarnation: self.incarnation,
+            tags: self.tags.clone(),
+            from,
+        }
+    }
+}
+
+/**Suspicion of a member and the nodes that independently confirmed it*/
+#[derive(Debug, Clone)]
+struct Suspicion {
+    started_at: Instant,
+    suspected_by: Uuid,
+    confirmations: HashSet<Uuid>,
+}
+
+/**Lifeguard local health multiplier. It grows while the local node misses acks,
+so probe interval and timeouts are lengthened instead of accusing healthy peers*/
+struct LocalHealth {
+    score: Mutex<u32>,
+    max: u32,
+}
+
+impl LocalHealth {
+    fn new(max: u32) -> LocalHealth {
+        LocalHealth {
+            score: Mutex::new(0),
+            max,
+        }
+    }
+
+    fn apply_delta(&self, delta: i32) {
+        let mut score = self.score.lock().unwrap();
+        let new_score = (*score as i32 + delta).max(0) as u32;
+        *score = new_score.min(self.max);
+    }
+
+    fn scale(&self, duration: Duration) -> Duration {
+        duration * (*self.score.lock().unwrap() + 1)
+    }
+}
+
+#[derive(Debug)]
+struct QueuedUpdate {
+    update: MembershipUpdate,
+    transmits: u32,
+}
+
+/**Membership updates waiting to be piggybacked. Least transmitted go first*/
+struct UpdateQueue {
+    entries: Mutex<Vec<QueuedUpdate>>,
+}
+
+impl UpdateQueue {
+    fn new() -> UpdateQueue {
+        UpdateQueue {
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /**Adds an update. It invalidates any queued update about the same node*/
+    fn push(&self, update: MembershipUpdate) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| e.update.node.id != update.node.id);
+        entries.push(QueuedUpdate {
+            update,
+            transmits: 0,
+        });
+    }
+
+    fn take(&self, max: usize, retransmit_limit: u32) -> Vec<MembershipUpdate> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.sort_by_key(|e| e.transmits);
+
+        let taken = entries
+            .iter_mut()
+            .take(max)
+            .map(|e| {
+                e.transmits += 1;
+                e.update.clone()
+            })
+            .collect();
+
+        entries.retain(|e| e.transmits < retransmit_limit);
+        taken
+    }
+}
+
+/**Outcome of an alive update about a known or a new member*/
+#[derive(Debug, PartialEq)]
+enum AliveChange {
+    Added,
+    Updated,
+    /**Member came back at another address*/
+    Moved,
+    Unchanged,
+}
+
+/**Member that was removed from the cluster. It prevents stale alive updates and
+multicast announcements from re-adding the member until it is reaped*/
+#[derive(Debug, Clone)]
+struct Tombstone {
+    state: MemberState,
+    removed_at: Instant,
+}
+
+/**Tracks the largest cluster size seen within the window. A partition is suspected when
+the cluster shrinks by the threshold fraction of that size, and healed once at most half
+of that fraction is still missing. A cluster that stays shrunk for the rebaseline period
+is taken as the new normal, which is reported as healed too*/
+struct PartitionDetector {
+    window: Duration,
+    threshold: f64,
+    rebaseline: Duration,
+    sizes: VecDeque<(Instant, usize)>,
+    suspected_peak: Option<(usize, Instant)>,
+}
+
+impl PartitionDetector {
+    fn new(window: Duration, threshold: f64, rebaseline: Duration) -> PartitionDetector {
+        PartitionDetector {
+            window,
+            threshold,
+            rebaseline,
+            sizes: VecDeque::new(),
+            suspected_peak: None,
+        }
+    }
+
+    /**Records the current cluster size and returns an event if the state has changed*/
+    fn observe(&mut self, cluster_size: usize) -> Option<Event> {
+        let now = Instant::now();
+        while let Some(&(at, _)) = self.sizes.front() {
+            if now.duration_since(at) > self.window {
+                self.sizes.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.sizes.push_back((now, cluster_size));
+
+        match self.suspected_peak {
+            None => {
+                let peak_size = self.sizes.iter().map(|&(_, s)| s).max().unwrap();
+                if missing_fraction(peak_size, cluster_size) >= self.threshold {
+                    self.suspected_peak = Some((peak_size, now));
+                    Some(PartitionSuspected {
+                        cluster_size,
+                        peak_size,
+                    })
+                } else {
+                    None
+                }
+            }
+            Some((peak_size, suspected_at)) => {
+                let healed = missing_fraction(peak_size, cluster_size) < self.threshold / 2_f64;
+                let rebaselined = now.duration_since(suspected_at) >= self.rebaseline;
+
+                if healed || rebaselined {
+                    self.suspected_peak = None;
+                    // the old peak must not trigger the suspicion again
+                    self.sizes.retain(|&(at, _)| at >= suspected_at);
+                    Some(PartitionHealed { cluster_size })
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn is_suspected(&self) -> bool {
+        self.suspected_peak.is_some()
+    }
+}
+
+fn missing_fraction(peak_size: usize, cluster_size: usize) -> f64 {
+    if peak_size == 0 || cluster_size >= peak_size {
+        return 0_f64;
+    }
+    (peak_size - cluster_size) as f64 / peak_size as f64
+}
+
+/**Randomized round-robin order in which members are probed*/
+struct ProbeList {
+    order: Vec<NodeMeta>,
+    next: usize,
+}
+
+impl ProbeList {
+    fn new() -> ProbeList {
+        ProbeList {
+            order: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /**Returns the next member to probe. The list is reshuffled once a pass is over*/
+    fn next_member<R: Rng>(&mut self, members: &[NodeMeta], rng: &mut R) -> Option<NodeMeta> {
+        if self.next >= self.order.len() {
+            self.order = members.to_vec();
+            self.order.shuffle(rng);
+            self.next = 0;
+        }
+
+        let member = self.order.get(self.next).cloned();
+        self.next += 1;
+        member
+    }
+
+    /**New members are inserted at a random position of the current pass*/
+    fn insert<R: Rng>(&mut self, node: NodeMeta, rng: &mut R) {
+        let pos = rng.gen_range(0, self.order.len() + 1);
+        self.order.insert(pos, node);
+        if pos < self.next {
+            self.next += 1;
+        }
+    }
+
+    /**Member keeps its position, e.g. when its address has changed*/
+    fn replace(&mut self, node: NodeMeta) {
+        if let Some(n) = self.order.iter_mut().find(|n| n.id == node.id) {
+            *n = node;
+        }
+    }
+
+    fn remove(&mut self, node_id: &Uuid) {
+        if let Some(pos) = self.order.iter().position(|n| n.id == *node_id) {
+            self.order.remove(pos);
+            if pos < self.next {
+                self.next -= 1;
+            }
+        }
+    }
+}
+
 /**SWIM protocol logic and process*/
 struct SwimProtocol {
     local_node_meta: NodeMeta,
     config: DiscoveryConfig,
-    members: Arc<RwLock<Vec<NodeMeta>>>,
+    incarnation: Mutex<u64>,
+    local_tags: RwLock<HashMap<String, String>>,
+    // alive and suspected members
+    states: RwLock<HashMap<Uuid, MemberState>>,
+    tombstones: RwLock<HashMap<Uuid, Tombstone>>,
+    // ids purged by force_leave and when. Reaped together with the tombstones
+    purged: RwLock<HashMap<Uuid, Instant>>,
+    started_at: SystemTime,
+    coordinate: Mutex<CoordinateClient>,
+    partition: Mutex<PartitionDetector>,
+    detector: Box<dyn FailureDetector + Send + Sync>,
+    probe_list: Mutex<ProbeList>,
+    updates: UpdateQueue,
+    health: LocalHealth,
     messaging_service: Arc<RwLock<MessagingService>>,
-    // left members queue
-    event_loop: Arc<RwLock<EventLoop>>,
+    event_loop: Arc<EventLoop>,
 }
 
 impl SwimProtocol {
@@ -163,106 +690,998 @@ impl SwimProtocol {
         local_node_meta: NodeMeta,
         config: DiscoveryConfig,
         messaging_service: Arc<RwLock<MessagingService>>,
-        event_loop: Arc<RwLock<EventLoop>>,
+        event_loop: Arc<EventLoop>,
     ) -> SwimProtocol {
+        let local_tags = RwLock::new(config.tags.clone());
+        let health = LocalHealth::new(config.awareness_max_multiplier);
+        let detector = detector::from_config(&config);
+        let partition = PartitionDetector::new(
+            Duration::from_millis(config.partition_window_ms),
+            config.partition_threshold,
+            Duration::from_millis(config.partition_rebaseline_ms),
+        );
+
         SwimProtocol {
             local_node_meta,
             config,
-            members: Arc::new(RwLock::new(Vec::new())),
+            incarnation: Mutex::new(0),
+            local_tags,
+            states: RwLock::new(HashMap::new()),
+            tombstones: RwLock::new(HashMap::new()),
+            purged: RwLock::new(HashMap::new()),
+            started_at: SystemTime::now(),
+            coordinate: Mutex::new(CoordinateClient::new()),
+            partition: Mutex::new(partition),
+            detector,
+            probe_list: Mutex::new(ProbeList::new()),
+            updates: UpdateQueue::new(),
+            health,
             messaging_service,
             event_loop,
         }
     }
 
+    /**Members are probed in a randomized round-robin order: every pass over the shuffled
+    member list probes each member exactly once. Hence a failed member is probed within
+    2 * N protocol periods (rate_ms) in the worst case, where N is the cluster size*/
     fn start(&self) {
-        let mut rng = &mut rand::thread_rng();
+        let rng = &mut rand::thread_rng();
+
+        self.updates.push(self.local_update());
 
         loop {
+            let members_ = self.members();
+
             println!(
                 "[MembershipService]: Available members: {:?}",
-                self.members.read().unwrap().as_slice()
+                members_.as_slice()
             );
 
-            let members_ = self.members.read().unwrap().clone();
-
-            let member_to_probe: Option<NodeMeta> = members_.choose(rng).map(|n| n.clone());
+            let member_to_probe: Option<NodeMeta> =
+                self.probe_list.lock().unwrap().next_member(&members_, rng);
             if let Some(member_to_probe) = member_to_probe {
-                if let Err(_) = self.probe_member(&member_to_probe) {
-                    let other_members: Vec<&NodeMeta> = members_
-                        .choose_multiple(rng, self.config.fanout as usize)
-                        .collect();
-
-                    let is_available = other_members
-                        .into_iter()
-                        .map(|member| self.probe_request_member(&member_to_probe, member))
-                        .find_map(|result| result.ok())
-                        .is_some();
-
-                    if !is_available {
-                        self.remove_member(member_to_probe);
+                // probe and its indirect probes form one trace
+                let context = TraceContext::new_root();
+                let _context = trace::enter(Some(context));
+                let span = tracing::debug_span!(
+                    "hover.probe",
+                    member = %member_to_probe.id,
+                    traceparent = %context
+                );
+                let _span = span.enter();
+
+                match self.probe_member(&member_to_probe) {
+                    Ok(_) => {
+                        self.health.apply_delta(-1);
+                        self.detector.heartbeat(&member_to_probe.id);
+                    }
+                    Err(_) => {
+                        let other_members: Vec<&NodeMeta> = members_
+                            .iter()
+                            .filter(|member| member.id != member_to_probe.id)
+                            .collect::<Vec<_>>()
+                            .choose_multiple(rng, self.config.fanout as usize)
+                            .cloned()
+                            .collect();
+
+                        let mut is_available = false;
+                        let mut missed_nacks = 0;
+                        for member in other_members.into_iter() {
+                            match self.probe_request_member(&member_to_probe, member) {
+                                Ok(true) => {
+                                    is_available = true;
+                                    break;
+                                }
+                                Ok(false) => {}
+                                Err(_) => missed_nacks += 1,
+                            }
+                        }
+
+                        // missed nacks mean the local node is likely the slow one
+                        self.health.apply_delta(missed_nacks);
+
+                        if is_available {
+                            self.detector.heartbeat(&member_to_probe.id);
+                        } else {
+                            self.health.apply_delta(1);
+                            self.detector.probe_failed(&member_to_probe.id);
+                        }
                     }
                 }
             }
 
-            std::thread::sleep(Duration::from_millis(self.config.rate_ms));
+            self.suspect_failed_members();
+            self.remove_expired_suspects();
+            self.reap_tombstones();
+            self.check_partition();
+
+            std::thread::sleep(
+                self.health
+                    .scale(Duration::from_millis(self.config.rate_ms)),
+            );
         }
     }
 
-    fn probe_member(&self, member_to_probe: &NodeMeta) -> Result<(), Box<Error>> {
-        self.messaging_service
+    fn probe_member(&self, member_to_probe: &NodeMeta) -> Result<(), Box<dyn Error>> {
+        let payload = ProbePayload {
+            updates: self.take_updates(),
+        };
+
+        let payload_bytes = serialize::to_bytes(&payload).unwrap();
+
+        let sent_at = Instant::now();
+        let response = self
+            .messaging_service
             .read()
             .unwrap()
             .send_to_member_receive_type(
-                Vec::new(),
+                payload_bytes,
                 member_to_probe,
                 MessageType::Probe,
-                Duration::from_millis(self.config.probe_timeout_ms),
-            )
-            .map(|_| ())
+                self.health
+                    .scale(Duration::from_millis(self.config.probe_timeout_ms)),
+            )?;
+        let rtt = sent_at.elapsed();
+
+        let coordinate = self.handle_ack(&response)?.coordinate;
+
+        if let Some(state) = self.states.write().unwrap().get_mut(&member_to_probe.id) {
+            state.last_probed_at = Some(SystemTime::now());
+            state.last_rtt = Some(rtt);
+
+            self.coordinate
+                .lock()
+                .unwrap()
+                .update(&member_to_probe.id, &coordinate, rtt);
+            state.coordinate = Some(coordinate);
+        }
+
+        Ok(())
     }
 
+    /**Returns false if the member responded with a nack*/
     fn probe_request_member(
         &self,
         member_to_probe: &NodeMeta,
         member: &NodeMeta,
-    ) -> Result<(), Box<Error>> {
+    ) -> Result<bool, Box<dyn Error>> {
         let payload = ProbeReqPayload {
             node: member_to_probe.clone(),
+            updates: self.take_updates(),
         };
 
         let payload_bytes = serialize::to_bytes(&payload).unwrap();
 
-        self.messaging_service
+        let response = self
+            .messaging_service
             .read()
             .unwrap()
             .send_to_member_receive_type(
                 payload_bytes,
                 member,
                 MessageType::ProbeReq,
-                Duration::from_millis(self.config.probe_req_timeout_ms),
-            )
-            .map(|_| ())
+                self.health
+                    .scale(Duration::from_millis(self.config.probe_req_timeout_ms)),
+            )?;
+
+        let ack = self.handle_ack(&response)?;
+        if !ack.nack {
+            // the member answered the indirect probe
+            if let Some(state) = self.states.write().unwrap().get_mut(&member_to_probe.id) {
+                state.last_probed_at = Some(SystemTime::now());
+            }
+        }
+
+        Ok(!ack.nack)
     }
 
-    fn add_member(&self, node: NodeMeta) {
-        if self.local_node_meta.id != node.id && !self.members.read().unwrap().contains(&node) {
-            println!("[MembershipService]: Added node to cluster {:?}", &node);
-            self.members.write().unwrap().push(node.clone());
-            self.event_loop
-                .read()
-                .unwrap()
-                .post_event(MemberAdded { node_meta: node });
+    /**Applies piggybacked updates and returns the rest of the ack*/
+    fn handle_ack(&self, response: &Message) -> Result<AckPayload, Box<dyn Error>> {
+        let mut ack = serialize::from_bytes::<AckPayload>(response.payload.as_slice())?;
+        self.apply_updates(std::mem::take(&mut ack.updates));
+        Ok(ack)
+    }
+
+    fn build_ack_payload(&self, nack: bool) -> Vec<u8> {
+        let payload = AckPayload {
+            nack,
+            updates: self.take_updates(),
+            coordinate: self.coordinate.lock().unwrap().coordinate(),
+        };
+
+        serialize::to_bytes(&payload).unwrap()
+    }
+
+    fn take_updates(&self) -> Vec<MembershipUpdate> {
+        self.updates
+            .take(MAX_PIGGYBACKED_UPDATES, self.retransmit_limit())
+    }
+
+    /**Every update is retransmitted retransmit_mult * log(N) times*/
+    fn retransmit_limit(&self) -> u32 {
+        self.config.retransmit_mult * cluster_size_log(self.cluster_size())
+    }
+
+    /**Alive and suspected members*/
+    fn members(&self) -> Vec<NodeMeta> {
+        self.states
+            .read()
+            .unwrap()
+            .values()
+            .map(|state| state.node.clone())
+            .collect()
+    }
+
+    /**Number of nodes including the local one*/
+    fn cluster_size(&self) -> usize {
+        self.states.read().unwrap().len() + 1
+    }
+
+    fn local_update(&self) -> MembershipUpdate {
+        MembershipUpdate {
+            node: self.local_node_meta.clone(),
+            status: MemberStatus::Alive,
+            incarnation: *self.incarnation.lock().unwrap(),
+            tags: self.local_tags.read().unwrap().clone(),
+            from: self.local_node_meta.id,
         }
     }
 
-    fn remove_member(&self, node: NodeMeta) {
-        if self.members.read().unwrap().contains(&&node) {
-            println!("[MembershipService]: Removed node from cluster {:?}", &node);
-            self.members.write().unwrap().retain(|x| x != &node);
-            self.event_loop
+    fn all_members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self
+            .states
+            .read()
+            .unwrap()
+            .values()
+            .map(|state| state.to_member())
+            .collect();
+
+        members.extend(
+            self.tombstones
                 .read()
                 .unwrap()
-                .post_event(MemberLeft { node_meta: node });
+                .values()
+                .map(|tombstone| tombstone.state.to_member()),
+        );
+        members
+    }
+
+    fn local_member(&self) -> Member {
+        let local_update = self.local_update();
+
+        Member {
+            node: local_update.node,
+            status: MemberStatus::Alive,
+            incarnation: local_update.incarnation,
+            tags: local_update.tags,
+            joined_at: self.started_at,
+            last_probed_at: None,
+            last_rtt: None,
+        }
+    }
+
+    fn coordinate(&self, node_id: &Uuid) -> Option<Coordinate> {
+        if *node_id == self.local_node_meta.id {
+            return Some(self.coordinate.lock().unwrap().coordinate());
+        }
+
+        self.states
+            .read()
+            .unwrap()
+            .get(node_id)
+            .and_then(|state| state.coordinate.clone())
+    }
+
+    fn set_local_tags(&self, tags: HashMap<String, String>) {
+        *self.local_tags.write().unwrap() = tags;
+        *self.incarnation.lock().unwrap() += 1;
+        self.updates.push(self.local_update());
+    }
+
+    /**Overrides a suspicion or a death claim about the local node*/
+    fn refute(&self, incarnation: u64) {
+        {
+            let mut local = self.incarnation.lock().unwrap();
+            if incarnation < *local {
+                return;
+            }
+            *local = incarnation + 1;
+        }
+        println!(
+            "[MembershipService]: Refuted suspicion with incarnation {}",
+            incarnation + 1
+        );
+        // being suspected is a hint that the local node may be degraded
+        self.health.apply_delta(1);
+        self.updates.push(self.local_update());
+    }
+
+    fn apply_updates(&self, updates: Vec<MembershipUpdate>) {
+        for update in updates.into_iter() {
+            match update.status {
+                MemberStatus::Alive => {
+                    self.handle_alive(update);
+                }
+                MemberStatus::Suspect => self.handle_suspect(update),
+                MemberStatus::Dead | MemberStatus::Left => self.handle_dead(update),
+            }
+        }
+    }
+
+    /**Returns true if the member was not known before*/
+    fn handle_alive(&self, update: MembershipUpdate) -> bool {
+        if update.node.id == self.local_node_meta.id {
+            return false;
+        }
+
+        if self.purged.read().unwrap().contains_key(&update.node.id) {
+            return false;
         }
+
+        // dead member is re-admitted only with a strictly newer incarnation
+        if let Some(tombstone) = self.tombstones.read().unwrap().get(&update.node.id) {
+            if update.incarnation <= tombstone.state.incarnation {
+                return false;
+            }
+        }
+
+        // states are released before other locks are taken and events are posted
+        let (change, member) = {
+            let mut states = self.states.write().unwrap();
+
+            match states.get_mut(&update.node.id) {
+                None => {
+                    let state = MemberState {
+                        node: update.node.clone(),
+                        status: MemberStatus::Alive,
+                        incarnation: update.incarnation,
+                        tags: update.tags.clone(),
+                        suspicion: None,
+                        joined_at: SystemTime::now(),
+                        last_probed_at: None,
+                        last_rtt: None,
+                        coordinate: None,
+                    };
+                    let member = state.to_member();
+                    states.insert(update.node.id, state);
+                    (AliveChange::Added, member)
+                }
+                Some(state) => {
+                    if update.incarnation <= state.incarnation {
+                        return false;
+                    }
+
+                    let moved = state.node.addr != update.node.addr;
+                    let changed = state.tags != update.tags || state.status != MemberStatus::Alive;
+
+                    state.node = update.node.clone();
+                    state.status = MemberStatus::Alive;
+                    state.incarnation = update.incarnation;
+                    state.tags = update.tags.clone();
+                    state.suspicion = None;
+
+                    let change = match (moved, changed) {
+                        (true, _) => AliveChange::Moved,
+                        (false, true) => AliveChange::Updated,
+                        (false, false) => AliveChange::Unchanged,
+                    };
+                    (change, state.to_member())
+                }
+            }
+        };
+
+        match change {
+            AliveChange::Added => {
+                self.tombstones.write().unwrap().remove(&update.node.id);
+                println!(
+                    "[MembershipService]: Added node to cluster {:?}",
+                    &update.node
+                );
+                self.probe_list
+                    .lock()
+                    .unwrap()
+                    .insert(update.node.clone(), &mut rand::thread_rng());
+                self.post_event(MemberAdded { member });
+            }
+            AliveChange::Moved => {
+                println!(
+                    "[MembershipService]: Node moved to a new address {:?}",
+                    &update.node
+                );
+                self.probe_list.lock().unwrap().replace(update.node.clone());
+                self.post_event(MemberUpdated { member });
+            }
+            AliveChange::Updated => {
+                self.post_event(MemberUpdated { member });
+            }
+            AliveChange::Unchanged => {}
+        }
+
+        self.updates.push(update);
+        change == AliveChange::Added
+    }
+
+    fn handle_suspect(&self, update: MembershipUpdate) {
+        if update.node.id == self.local_node_meta.id {
+            self.refute(update.incarnation);
+            return;
+        }
+
+        // events are posted and updates queued once the states are released
+        let suspected = {
+            let mut states = self.states.write().unwrap();
+            let state = match states.get_mut(&update.node.id) {
+                Some(state) => state,
+                None => return,
+            };
+            let is_newer = match state.status {
+                MemberStatus::Alive => update.incarnation >= state.incarnation,
+                _ => update.incarnation > state.incarnation,
+            };
+
+            if is_newer {
+                println!("[MembershipService]: Suspected node {:?}", &state.node);
+                state.status = MemberStatus::Suspect;
+                state.incarnation = update.incarnation;
+                state.suspicion = Some(Suspicion {
+                    started_at: Instant::now(),
+                    suspected_by: update.from,
+                    confirmations: HashSet::new(),
+                });
+                Some(state.to_member())
+            } else if update.incarnation == state.incarnation && update.from != state.node.id {
+                // independent confirmation of the current suspicion
+                match state.suspicion.as_mut() {
+                    Some(suspicion) if suspicion.suspected_by != update.from => {
+                        if !suspicion.confirmations.insert(update.from) {
+                            return;
+                        }
+                    }
+                    _ => return,
+                }
+                None
+            } else {
+                return;
+            }
+        };
+
+        if let Some(member) = suspected {
+            self.post_event(MemberSuspected { member });
+        }
+        self.updates.push(update);
+    }
+
+    fn handle_dead(&self, update: MembershipUpdate) {
+        if update.node.id == self.local_node_meta.id {
+            self.refute(update.incarnation);
+            return;
+        }
+
+        let is_newer = self
+            .states
+            .read()
+            .unwrap()
+            .get(&update.node.id)
+            .map(|state| update.incarnation >= state.incarnation)
+            .unwrap_or(false);
+
+        if is_newer {
+            self.remove_member(update.node, update.status);
+        }
+    }
+
+    /**Adds a member announced via multicast*/
+    fn add_member(&self, update: MembershipUpdate) {
+        if self.handle_alive(update) {
+            // let the new member learn about us
+            self.updates.push(self.local_update());
+        }
+    }
+
+    /**Suspects every alive member the failure detector considers unavailable*/
+    fn suspect_failed_members(&self) {
+        let members_ = self.members();
+
+        for member in members_.iter() {
+            if !self.detector.is_available(&member.id) {
+                self.suspect_member(member);
+            }
+        }
+    }
+
+    /**Marks a member that the failure detector considers unavailable*/
+    fn suspect_member(&self, node: &NodeMeta) {
+        let update = self
+            .states
+            .read()
+            .unwrap()
+            .get(&node.id)
+            .filter(|state| state.status == MemberStatus::Alive)
+            .map(|state| MembershipUpdate {
+                status: MemberStatus::Suspect,
+                ..state.to_update(self.local_node_meta.id)
+            });
+
+        if let Some(update) = update {
+            self.handle_suspect(update);
+        }
+    }
+
+    fn remove_expired_suspects(&self) {
+        let states = self.states.read().unwrap();
+        let cluster_size = states.len() + 1;
+
+        let expired: Vec<NodeMeta> = states
+            .values()
+            .filter(|state| match state.suspicion {
+                Some(ref suspicion) => {
+                    let timeout = suspicion_timeout(
+                        &self.config,
+                        cluster_size,
+                        suspicion.confirmations.len(),
+                    );
+                    state.status == MemberStatus::Suspect
+                        && suspicion.started_at.elapsed() >= timeout
+                }
+                None => false,
+            })
+            .map(|state| state.node.clone())
+            .collect();
+        drop(states);
+
+        for node in expired.into_iter() {
+            self.remove_member(node, MemberStatus::Dead);
+        }
+    }
+
+    fn remove_member(&self, node: NodeMeta, status: MemberStatus) {
+        self.evict_member(&node.id, status);
+    }
+
+    /**Evicts a member and blocks its id until it is reaped. Returns false if the member
+    is not known at all*/
+    fn force_leave(&self, node_id: &Uuid) -> bool {
+        let known = self.states.read().unwrap().contains_key(node_id)
+            || self.tombstones.read().unwrap().contains_key(node_id);
+        if !known {
+            return false;
+        }
+
+        // blocked first, so a concurrent alive update cannot re-add it
+        self.purged
+            .write()
+            .unwrap()
+            .insert(*node_id, Instant::now());
+        self.evict_member(node_id, MemberStatus::Left);
+        true
+    }
+
+    /**Removes a member and replaces it with a tombstone. Returns false if the member
+    was not alive or suspected*/
+    fn evict_member(&self, node_id: &Uuid, status: MemberStatus) -> bool {
+        let removed = self.states.write().unwrap().remove(node_id);
+
+        match removed {
+            Some(mut state) => {
+                state.status = status;
+                state.suspicion = None;
+
+                println!(
+                    "[MembershipService]: Removed node from cluster {:?}",
+                    &state.node
+                );
+                self.detector.forget(node_id);
+                self.coordinate.lock().unwrap().forget(node_id);
+                self.probe_list.lock().unwrap().remove(node_id);
+                self.updates.push(state.to_update(self.local_node_meta.id));
+                self.tombstones.write().unwrap().insert(
+                    *node_id,
+                    Tombstone {
+                        state: state.clone(),
+                        removed_at: Instant::now(),
+                    },
+                );
+
+                self.post_event(MemberLeft {
+                    member: state.to_member(),
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn check_partition(&self) {
+        let cluster_size = self.cluster_size();
+
+        let event = self.partition.lock().unwrap().observe(cluster_size);
+        if let Some(event) = event {
+            println!(
+                "[MembershipService]: Partition state changed. Cluster size: {}",
+                cluster_size
+            );
+            self.post_event(event);
+        }
+    }
+
+    fn reap_tombstones(&self) {
+        let reap_interval = Duration::from_millis(self.config.tombstone_reap_ms);
+
+        self.tombstones
+            .write()
+            .unwrap()
+            .retain(|_, t| t.removed_at.elapsed() < reap_interval);
+        self.purged
+            .write()
+            .unwrap()
+            .retain(|_, purged_at| purged_at.elapsed() < reap_interval);
+    }
+
+    fn post_event(&self, event: Event) {
+        self.event_loop.post_event(event);
+    }
+}
+
+/**Whether the cluster holds the majority of the expected size*/
+fn has_quorum(cluster_size: usize, expected_size: usize) -> bool {
+    cluster_size > expected_size / 2
+}
+
+/**Up to n members with the lowest estimated RTT to the local coordinate*/
+fn nearest(local: &Coordinate, members: Vec<(Member, Coordinate)>, n: usize) -> Vec<Member> {
+    let mut members: Vec<(Duration, Member)> = members
+        .into_iter()
+        .map(|(m, c)| (local.distance_to(&c), m))
+        .collect();
+
+    members.sort_by_key(|(rtt, _)| *rtt);
+    members.into_iter().take(n).map(|(_, m)| m).collect()
+}
+
+/**Suspected member is declared dead after a timeout that starts at
+suspicion_max_timeout_mult * min and shrinks logarithmically down to
+min = suspicion_mult * log(N) protocol periods as independent confirmations arrive*/
+fn suspicion_timeout(
+    config: &DiscoveryConfig,
+    cluster_size: usize,
+    confirmations: usize,
+) -> Duration {
+    let periods = config.suspicion_mult * cluster_size_log(cluster_size);
+    let min = (config.rate_ms * periods as u64) as f64;
+    let max = min * config.suspicion_max_timeout_mult as f64;
+
+    // confirmations are expected from the other members, the suspect excluded
+    let expected = config.fanout.min(cluster_size.saturating_sub(2) as u32);
+    if expected == 0 {
+        return Duration::from_millis(min as u64);
+    }
+
+    let frac = (confirmations as f64 + 1_f64).ln() / (expected as f64 + 1_f64).ln();
+    let timeout = (max - (max - min) * frac).max(min);
+    Duration::from_millis(timeout as u64)
+}
+
+/**Logarithm of the cluster size the gossip parameters are scaled with, at least 1*/
+fn cluster_size_log(cluster_size: usize) -> u32 {
+    ((cluster_size as f64).log10().ceil() as u32).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::net::Ipv4Addr;
+
+    fn node(port: u16) -> NodeMeta {
+        NodeMeta {
+            id: Uuid::new_v4(),
+            addr: Address {
+                ip: Ipv4Addr::LOCALHOST,
+                port,
+            },
+        }
+    }
+
+    fn alive(node: &NodeMeta, incarnation: u64) -> MembershipUpdate {
+        MembershipUpdate {
+            node: node.clone(),
+            status: MemberStatus::Alive,
+            incarnation,
+            tags: HashMap::new(),
+            from: node.id,
+        }
+    }
+
+    fn seeded() -> StdRng {
+        StdRng::seed_from_u64(7)
+    }
+
+    /**Drains the rest of the current pass*/
+    fn rest_of_pass(list: &mut ProbeList, members: &[NodeMeta], rng: &mut StdRng) -> Vec<Uuid> {
+        let mut probed = Vec::new();
+        while list.next < list.order.len() {
+            probed.push(list.next_member(members, rng).unwrap().id);
+        }
+        probed
+    }
+
+    fn sorted(mut ids: Vec<Uuid>) -> Vec<Uuid> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn pass_probes_every_member_once() {
+        let members: Vec<NodeMeta> = (0..5).map(node).collect();
+        let mut list = ProbeList::new();
+        let mut rng = seeded();
+
+        for _ in 0..3 {
+            let mut probed = vec![list.next_member(&members, &mut rng).unwrap().id];
+            probed.extend(rest_of_pass(&mut list, &members, &mut rng));
+            assert_eq!(
+                sorted(probed),
+                sorted(members.iter().map(|m| m.id).collect())
+            );
+        }
+    }
+
+    #[test]
+    fn probe_order_is_reproducible_with_the_same_rng() {
+        let members: Vec<NodeMeta> = (0..8).map(node).collect();
+        let new = node(100);
+        let order = |rng: &mut StdRng| {
+            let mut list = ProbeList::new();
+            let first = list.next_member(&members, rng).unwrap().id;
+            list.insert(new.clone(), rng);
+            let mut probed = vec![first];
+            probed.extend(rest_of_pass(&mut list, &members, rng));
+            probed
+        };
+
+        assert_eq!(order(&mut seeded()), order(&mut seeded()));
+    }
+
+    #[test]
+    fn inserted_member_does_not_skip_others() {
+        let members: Vec<NodeMeta> = (0..6).map(node).collect();
+        let mut rng = seeded();
+
+        for _ in 0..20 {
+            let mut list = ProbeList::new();
+            let mut probed = vec![list.next_member(&members, &mut rng).unwrap().id];
+            probed.push(list.next_member(&members, &mut rng).unwrap().id);
+
+            let new = node(100);
+            list.insert(new.clone(), &mut rng);
+            probed.extend(rest_of_pass(&mut list, &members, &mut rng));
+
+            // every old member exactly once, the new one at most once
+            let old: Vec<Uuid> = probed.iter().cloned().filter(|id| *id != new.id).collect();
+            assert_eq!(sorted(old), sorted(members.iter().map(|m| m.id).collect()));
+            assert!(probed.iter().filter(|id| **id == new.id).count() <= 1);
+        }
+    }
+
+    #[test]
+    fn removed_member_is_not_probed_and_does_not_skip_others() {
+        let members: Vec<NodeMeta> = (0..6).map(node).collect();
+        let mut rng = seeded();
+
+        for removed_at in 0..6 {
+            let mut list = ProbeList::new();
+            let mut probed = vec![list.next_member(&members, &mut rng).unwrap().id];
+            probed.push(list.next_member(&members, &mut rng).unwrap().id);
+
+            let removed = list.order[removed_at].id;
+            list.remove(&removed);
+            probed.extend(rest_of_pass(&mut list, &members, &mut rng));
+
+            let expected: Vec<Uuid> = members
+                .iter()
+                .map(|m| m.id)
+                .filter(|id| *id != removed || probed[..2].contains(id))
+                .collect();
+            assert_eq!(sorted(probed), sorted(expected));
+        }
+    }
+
+    fn discovery_config() -> DiscoveryConfig {
+        let mut config = crate::config::HoverConfig::default().unwrap().discovery;
+        config.rate_ms = 100;
+        config.fanout = 3;
+        config.suspicion_mult = 4;
+        config.suspicion_max_timeout_mult = 6;
+        config
+    }
+
+    #[test]
+    fn local_health_is_clamped() {
+        let health = LocalHealth::new(3);
+        let period = Duration::from_millis(100);
+
+        health.apply_delta(-1);
+        assert_eq!(health.scale(period), period);
+
+        health.apply_delta(2);
+        assert_eq!(health.scale(period), period * 3);
+
+        health.apply_delta(10);
+        assert_eq!(health.scale(period), period * 4);
+
+        health.apply_delta(-10);
+        assert_eq!(health.scale(period), period);
+    }
+
+    #[test]
+    fn suspicion_timeout_shrinks_with_confirmations() {
+        let config = discovery_config();
+        // 4 periods of 100ms, times log of a 10 node cluster
+        let min = Duration::from_millis(400);
+
+        assert_eq!(suspicion_timeout(&config, 10, 0), min * 6);
+        assert_eq!(suspicion_timeout(&config, 10, 3), min);
+        assert_eq!(suspicion_timeout(&config, 10, 10), min);
+
+        let mut previous = suspicion_timeout(&config, 10, 0);
+        for confirmations in 1..=3 {
+            let timeout = suspicion_timeout(&config, 10, confirmations);
+            assert!(timeout < previous);
+            previous = timeout;
+        }
+    }
+
+    #[test]
+    fn suspicion_timeout_is_min_without_other_members() {
+        let config = discovery_config();
+
+        // local node and the suspect only
+        assert_eq!(suspicion_timeout(&config, 2, 0), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn suspicion_timeout_scales_with_cluster_size() {
+        let config = discovery_config();
+
+        assert_eq!(
+            suspicion_timeout(&config, 50, 3),
+            Duration::from_millis(800)
+        );
+    }
+
+    fn member_at(position: f64) -> (Member, Coordinate) {
+        let mut coordinate = Coordinate::new();
+        coordinate.vec[0] = position;
+
+        let member = Member {
+            node: node(1),
+            status: MemberStatus::Alive,
+            incarnation: 0,
+            tags: HashMap::new(),
+            joined_at: SystemTime::now(),
+            last_probed_at: None,
+            last_rtt: None,
+        };
+        (member, coordinate)
+    }
+
+    #[test]
+    fn nearest_members_are_sorted_by_estimated_rtt() {
+        let members = vec![member_at(0.03), member_at(-0.01), member_at(0.02)];
+        let ids: Vec<Uuid> = members.iter().map(|(m, _)| m.node.id).collect();
+
+        let sorted: Vec<Uuid> = nearest(&Coordinate::new(), members.clone(), 3)
+            .iter()
+            .map(|m| m.node.id)
+            .collect();
+        assert_eq!(sorted, vec![ids[1], ids[2], ids[0]]);
+
+        assert_eq!(nearest(&Coordinate::new(), members, 2).len(), 2);
+    }
+
+    #[test]
+    fn quorum_needs_a_strict_majority() {
+        assert!(has_quorum(1, 1));
+        assert!(!has_quorum(1, 2));
+        assert!(has_quorum(2, 3));
+        assert!(!has_quorum(2, 4));
+        assert!(has_quorum(3, 4));
+        assert!(has_quorum(5, 4));
+    }
+
+    fn is_suspected(event: Option<Event>) -> bool {
+        matches!(event, Some(PartitionSuspected { .. }))
+    }
+
+    fn is_healed(event: Option<Event>) -> bool {
+        matches!(event, Some(PartitionHealed { .. }))
+    }
+
+    #[test]
+    fn partition_is_suspected_and_healed() {
+        let hour = Duration::from_secs(3600);
+        let mut detector = PartitionDetector::new(hour, 0.5, hour);
+
+        assert!(detector.observe(10).is_none());
+        assert!(detector.observe(6).is_none());
+        assert!(is_suspected(detector.observe(5)));
+        assert!(detector.is_suspected());
+
+        // 30% still missing is more than half of the threshold
+        assert!(detector.observe(7).is_none());
+        assert!(is_healed(detector.observe(8)));
+        assert!(!detector.is_suspected());
+    }
+
+    #[test]
+    fn shrunk_cluster_is_rebaselined() {
+        let hour = Duration::from_secs(3600);
+        let rebaseline = Duration::from_millis(20);
+        let mut detector = PartitionDetector::new(hour, 0.5, rebaseline);
+
+        detector.observe(10);
+        assert!(is_suspected(detector.observe(4)));
+        assert!(detector.observe(4).is_none());
+
+        std::thread::sleep(rebaseline);
+        assert!(is_healed(detector.observe(4)));
+        assert!(!detector.is_suspected());
+
+        // the old peak is forgotten
+        assert!(detector.observe(4).is_none());
+        assert!(!detector.is_suspected());
+    }
+
+    #[test]
+    fn cluster_size_log_is_at_least_one() {
+        assert_eq!(cluster_size_log(1), 1);
+        assert_eq!(cluster_size_log(10), 1);
+        assert_eq!(cluster_size_log(11), 2);
+        assert_eq!(cluster_size_log(100), 2);
+        assert_eq!(cluster_size_log(101), 3);
+    }
+
+    #[test]
+    fn update_is_dropped_after_retransmit_limit() {
+        let queue = UpdateQueue::new();
+        queue.push(alive(&node(1), 0));
+
+        for _ in 0..3 {
+            assert_eq!(queue.take(MAX_PIGGYBACKED_UPDATES, 3).len(), 1);
+        }
+        assert!(queue.take(MAX_PIGGYBACKED_UPDATES, 3).is_empty());
+    }
+
+    #[test]
+    fn least_transmitted_updates_are_taken_first() {
+        let queue = UpdateQueue::new();
+        let (a, b, c) = (node(1), node(2), node(3));
+
+        queue.push(alive(&a, 0));
+        assert_eq!(queue.take(1, 10)[0].node.id, a.id);
+
+        queue.push(alive(&b, 0));
+        queue.push(alive(&c, 0));
+        let taken: Vec<Uuid> = queue.take(2, 10).iter().map(|u| u.node.id).collect();
+        assert_eq!(taken.len(), 2);
+        assert!(taken.contains(&b.id) && taken.contains(&c.id));
+
+        // every update was sent once, so all of them fit again
+        assert_eq!(queue.take(MAX_PIGGYBACKED_UPDATES, 10).len(), 3);
+    }
+
+    #[test]
+    fn newer_update_replaces_queued_one() {
+        let queue = UpdateQueue::new();
+        let a = node(1);
+
+        queue.push(alive(&a, 0));
+        queue.take(1, 10);
+        queue.push(alive(&a, 1));
+
+        let taken = queue.take(MAX_PIGGYBACKED_UPDATES, 10);
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].incarnation, 1);
     }
 }
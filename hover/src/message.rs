@@ -2,54 +2,420 @@ extern crate chashmap;
 extern crate socket2;
 extern crate uuid;
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use std::io::Write;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
 
 use chashmap::CHashMap;
-use crossbeam_channel::{Receiver, Sender};
-use socket2::{Domain, SockAddr, Socket, Type};
+use crossbeam_channel::{select, Receiver, RecvTimeoutError, Sender};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
-use crate::common::{Address, BroadcastMessage, Message, MessageType, NodeMeta, ProbeReqPayload};
-use crate::events::Event::{BroadcastIn, ProbeIn, ProbeReqIn};
-use crate::events::{Event, EventListener, EventLoop};
+use crate::common::{
+    Address, GossipMessage, MembershipUpdate, Message, MessageType, NodeMeta, Priority,
+    ProbePayload, ProbeReqPayload, ReliableAck, ReliableMessage, RpcError, RpcRequest, RpcResponse,
+    StreamAck, StreamChunk,
+};
+use crate::config::{LaneConfig, MessagingConfig, StreamConfig};
+use crate::events::Event::{BroadcastIn, MembershipUpdatesIn, ProbeIn, ProbeReqIn};
+use crate::events::{
+    Event, EventListener, EventLoop, ListenerQueue, Listeners, Subscription, SubscriptionStream,
+};
+use crate::lanes::Lanes;
 use crate::membership::MembershipService;
+use crate::query::{QueryFilter, QueryResponse, QueryResponses};
+use crate::reliable::{Channel, NotDelivered, ReliableChannels};
+use crate::retry::{
+    CircuitBreakers, CircuitOpenError, ConnectError, RetryPolicy, SendOptions, UnknownMember,
+};
 use crate::serialize;
+use crate::serialize::CodecKind;
+use crate::trace::{self, TraceContext, TRACEPARENT};
+use crate::transfer::{read_chunk, IncomingStream, StreamError, Streams};
 
 use self::uuid::Uuid;
 
+/**Threads sending the requests of a single query*/
+const QUERY_SENDERS: usize = 8;
+/**Bounds connecting and writing, so an unreachable member does not hold a sender thread*/
+const SEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/**Incoming request that has to be answered. Responding consumes the request.
+If it is dropped without a response while the sender waits for one, the sender
+receives an error response*/
+pub struct Request {
+    msg: Arc<Message>,
+    local_node: NodeMeta,
+    codec: CodecKind,
+    outbox: Arc<Outbox>,
+    responded: bool,
+}
+
+impl Request {
+    pub fn payload(&self) -> &[u8] {
+        self.msg.payload.as_slice()
+    }
+
+    /**Node that sent the request. Responses are sent to its address*/
+    pub fn sender(&self) -> NodeMeta {
+        NodeMeta {
+            id: self.msg.sender_id,
+            addr: self.msg.return_address.clone(),
+        }
+    }
+
+    pub fn message(&self) -> &Arc<Message> {
+        &self.msg
+    }
+
+    /**Time the sender is still willing to wait. Work whose response would come
+    later can be skipped. None if the sender does not wait for a response*/
+    pub fn remaining(&self) -> Option<Duration> {
+        self.msg.remaining()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.msg.is_expired()
+    }
+
+    /**Trace context of the caller. It is also current while the handler runs*/
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        TraceContext::from_message(&self.msg)
+    }
+
+    pub fn respond(mut self, payload: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.responded = true;
+        self.send(MessageType::Response, payload)
+    }
+
+    /**Sender receives RpcError::HandlerFailed with the reason*/
+    pub fn respond_error(mut self, reason: &str) -> Result<(), Box<dyn Error>> {
+        self.responded = true;
+        let error = RpcError::HandlerFailed(reason.to_string());
+        self.send(MessageType::ErrorResponse, serialize::to_bytes(&error)?)
+    }
+
+    fn send(&self, msg_type: MessageType, payload: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let (priority, bytes) = self.frame(msg_type, payload)?;
+        self.outbox.send(priority, bytes, &self.msg.return_address)
+    }
+
+    fn frame(
+        &self,
+        msg_type: MessageType,
+        payload: Vec<u8>,
+    ) -> Result<(Priority, Vec<u8>), Box<dyn Error>> {
+        let msg = Message {
+            cor_id: self.msg.cor_id,
+            return_address: self.local_node.addr.clone(),
+            priority: self.msg.priority,
+            sender_id: self.local_node.id,
+            headers: response_headers(TraceContext::from_message(&self.msg)),
+            timeout_ms: None,
+            received_at: None,
+            msg_type,
+            payload,
+        };
+
+        Ok((msg.priority, serialize::to_frame(self.codec, &msg)?))
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        // nobody waits for the response of a one-way message
+        if !self.responded && self.msg.timeout_ms.is_some() {
+            let error = RpcError::HandlerFailed(String::from("Request dropped without a response"));
+            // may be dropped on the event loop, so the response is not waited for
+            let sent = serialize::to_bytes(&error)
+                .and_then(|payload| self.frame(MessageType::ErrorResponse, payload))
+                .and_then(|(priority, bytes)| {
+                    self.outbox.post(priority, bytes, &self.msg.return_address)
+                });
+
+            if let Err(_) = sent {
+                eprintln!("[MessageDispatcher]: Error while sending an error response!");
+            }
+        }
+    }
+}
+
+/**Incoming call handed over to the handler of its method*/
+struct RpcCall {
+    cor_id: Uuid,
+    return_address: Address,
+    deadline: Option<Instant>,
+    trace: Option<TraceContext>,
+    payload: Vec<u8>,
+}
+
+/**Outgoing messages. A pool of threads sends them, taking them from a lane per
+priority class, so a burst of user messages does not hold up probes and acks*/
+pub(crate) struct Outbox {
+    lanes: Arc<Lanes<Outgoing>>,
+}
+
+struct Outgoing {
+    bytes: Vec<u8>,
+    address: Address,
+    // None if nobody waits for the result
+    result: Option<Sender<Result<(), Box<dyn Error + Send>>>>,
+}
+
+impl Outbox {
+    pub(crate) fn new(config: &LaneConfig) -> Outbox {
+        let lanes = Arc::new(Lanes::new(config));
+
+        for _ in 0..config.sender_threads.max(1) {
+            let lanes_ = lanes.clone();
+            std::thread::spawn(move || loop {
+                let outgoing: Outgoing = lanes_.recv();
+                let result = send_bytes(outgoing.bytes, &outgoing.address);
+                match outgoing.result {
+                    Some(sender) => {
+                        let _ = sender.send(result);
+                    }
+                    None => {
+                        if let Err(err) = result {
+                            eprintln!(
+                                "[MessageDispatcher]: Error while sending to {:?}: {}",
+                                outgoing.address, err
+                            );
+                        }
+                    }
+                }
+            });
+        }
+
+        Outbox { lanes }
+    }
+
+    /**Messages dropped because their lane was full*/
+    pub(crate) fn dropped(&self) -> u64 {
+        self.lanes.dropped()
+    }
+
+    /**Blocks until the message is sent*/
+    pub(crate) fn send(
+        &self,
+        priority: Priority,
+        bytes: Vec<u8>,
+        address: &Address,
+    ) -> Result<(), Box<dyn Error>> {
+        let (s, r) = crossbeam_channel::bounded(1);
+        let size = bytes.len();
+        let outgoing = Outgoing {
+            bytes,
+            address: address.clone(),
+            result: Some(s),
+        };
+
+        self.lanes.push(priority, outgoing, size)?;
+        match r.recv() {
+            Ok(result) => result.map_err(|err| err as Box<dyn Error>),
+            Err(_) => Err(Box::from("Outbox is closed")),
+        }
+    }
+
+    /**Queues the message without waiting for it to be sent. Send errors are only
+    logged. Fails if the lane of the priority is full*/
+    pub(crate) fn post(
+        &self,
+        priority: Priority,
+        bytes: Vec<u8>,
+        address: &Address,
+    ) -> Result<(), Box<dyn Error>> {
+        let size = bytes.len();
+        let outgoing = Outgoing {
+            bytes,
+            address: address.clone(),
+            result: None,
+        };
+
+        self.lanes.push(priority, outgoing, size)
+    }
+}
+
 pub struct MessageDispatcher {
-    listeners: Vec<Box<Fn(Arc<Message>) -> () + 'static + Send + Sync>>,
+    local_node: NodeMeta,
+    codec: CodecKind,
+    listeners: Listeners<Arc<ListenerQueue<Arc<Message>>>>,
+    handlers: Arc<RwLock<HashMap<String, Arc<ListenerQueue<RpcCall>>>>>,
+    request_handler: Arc<RwLock<Option<Arc<ListenerQueue<Request>>>>>,
+    streams: Streams,
+    reliable: ReliableChannels,
     resp_callbacks: RwLock<CHashMap<Uuid, Sender<Arc<Message>>>>,
-    event_loop: Arc<RwLock<EventLoop>>,
+    outbox: Arc<Outbox>,
+    event_loop: Arc<EventLoop>,
 }
 
 impl MessageDispatcher {
-    pub fn new(event_loop: Arc<RwLock<EventLoop>>) -> MessageDispatcher {
+    pub(crate) fn new(
+        local_node: NodeMeta,
+        codec: CodecKind,
+        stream_config: StreamConfig,
+        lane_config: LaneConfig,
+        event_loop: Arc<EventLoop>,
+    ) -> MessageDispatcher {
         MessageDispatcher {
-            listeners: Vec::new(),
+            local_node,
+            codec,
+            listeners: Listeners::new(),
+            handlers: Arc::new(RwLock::new(HashMap::new())),
+            request_handler: Arc::new(RwLock::new(None)),
+            streams: Streams::new(&stream_config),
+            reliable: ReliableChannels::new(),
             resp_callbacks: RwLock::new(CHashMap::new()),
+            outbox: Arc::new(Outbox::new(&lane_config)),
             event_loop,
         }
     }
 
-    pub fn add_msg_listener<F>(&mut self, f: F) -> Result<(), ()>
+    /**Registers a handler of the method. Calls are handled on the handler's own thread.
+    A handler registered earlier for the same method is replaced. The request and response
+    types can be given explicitly, as in register_handler::<Req, Resp, _>(method, f)*/
+    pub fn register_handler<Req, Resp, F>(&self, method: &str, f: F) -> Subscription
     where
-        F: Fn(Arc<Message>) -> () + 'static + Send + Sync,
+        F: Fn(Req) -> Result<Resp, Box<dyn Error>> + 'static + Send + Sync,
+        Req: DeserializeOwned,
+        Resp: Serialize,
     {
-        self.listeners.push(Box::new(f));
-        Ok(())
+        let local_node = self.local_node.clone();
+        let codec = self.codec;
+        let outbox = self.outbox.clone();
+        let queue = self.event_loop.listener_queue(move |call: RpcCall| {
+            if let Some(deadline) = call.deadline {
+                if Instant::now() >= deadline {
+                    println!("[MessageDispatcher]: Skipped an expired call");
+                    return;
+                }
+            }
+
+            let _context = trace::enter(call.trace);
+            let span = tracing::debug_span!("hover.handle", cor_id = %call.cor_id);
+            let _span = span.enter();
+
+            let result = match serialize::from_bytes::<Req>(call.payload.as_slice()) {
+                Ok(req) => match f(req) {
+                    Ok(resp) => {
+                        serialize::to_bytes(&resp).map_err(|e| RpcError::Codec(e.to_string()))
+                    }
+                    Err(e) => Err(RpcError::HandlerFailed(e.to_string())),
+                },
+                Err(e) => Err(RpcError::Codec(e.to_string())),
+            };
+
+            reply_rpc(&local_node, codec, &outbox, &call, result);
+        });
+
+        let method = method.to_string();
+        if let Some(_) = self
+            .handlers
+            .write()
+            .unwrap()
+            .insert(method.clone(), queue.clone())
+        {
+            println!("[MessageDispatcher]: overrides a handler of {}!", method);
+        }
+
+        let handlers = Arc::downgrade(&self.handlers);
+        Subscription::new(move || {
+            queue.cancel();
+            if let Some(handlers) = handlers.upgrade() {
+                let mut handlers = handlers.write().unwrap();
+                let registered = match handlers.get(&method) {
+                    Some(h) => Arc::ptr_eq(h, &queue),
+                    None => false,
+                };
+                if registered {
+                    handlers.remove(&method);
+                }
+            }
+        })
     }
 
-    fn add_resp_callback(&self, msg_id: Uuid, sender: Sender<Arc<Message>>) {
-        match self.resp_callbacks.write().unwrap().insert(msg_id, sender) {
-            Some(_) => {
-                println!("[MessageDispatcher]: overrides a resp_callback!");
+    pub fn add_msg_listener<F>(&self, f: F) -> Subscription
+    where
+        F: Fn(Arc<Message>) + 'static + Send + Sync,
+    {
+        let queue = self.event_loop.listener_queue(move |msg: Arc<Message>| {
+            let _context = trace::enter(TraceContext::from_message(&msg));
+            let span = tracing::debug_span!("hover.handle", cor_id = %msg.cor_id);
+            let _span = span.enter();
+
+            f(msg)
+        });
+        self.listeners.add(queue.clone()).cancelling(queue)
+    }
+
+    /**Sets the handler that answers incoming requests. Unlike message listeners
+    it receives a Request that has to be responded to. A handler set earlier is replaced*/
+    pub fn set_request_handler<F>(&self, f: F) -> Subscription
+    where
+        F: Fn(Request) + 'static + Send + Sync,
+    {
+        let queue = self.event_loop.listener_queue(move |request: Request| {
+            let _context = trace::enter(request.trace_context());
+            let span = tracing::debug_span!("hover.handle", cor_id = %request.message().cor_id);
+            let _span = span.enter();
+
+            f(request)
+        });
+        if let Some(_) = self.request_handler.write().unwrap().replace(queue.clone()) {
+            println!("[MessageDispatcher]: overrides a request handler!");
+        }
+
+        let request_handler = Arc::downgrade(&self.request_handler);
+        Subscription::new(move || {
+            queue.cancel();
+            if let Some(request_handler) = request_handler.upgrade() {
+                let mut request_handler = request_handler.write().unwrap();
+                let registered = match *request_handler {
+                    Some(ref h) => Arc::ptr_eq(h, &queue),
+                    None => false,
+                };
+                if registered {
+                    request_handler.take();
+                }
             }
-            None => {}
+        })
+    }
+
+    /**Sets the handler of incoming streams. Each stream is read on its own thread,
+    up to the configured number of streams at a time. Streams are refused while no
+    handler is set. A handler set earlier is replaced*/
+    pub fn set_stream_handler<F>(&self, f: F) -> Subscription
+    where
+        F: Fn(IncomingStream) + 'static + Send + Sync,
+    {
+        self.streams.set_handler(f)
+    }
+
+    /**Channel of incoming requests. It is unregistered once the receiver is dropped*/
+    pub fn subscribe_messages(&self) -> Receiver<Arc<Message>> {
+        self.event_loop.subscribe(&self.listeners)
+    }
+
+    /**Async variant of subscribe_messages*/
+    pub fn subscribe_messages_stream(&self) -> SubscriptionStream<Arc<Message>> {
+        self.event_loop.subscribe_stream(&self.listeners)
+    }
+
+    pub(crate) fn outbox(&self) -> Arc<Outbox> {
+        self.outbox.clone()
+    }
+
+    /**Outgoing reliable channel to the peer*/
+    pub(crate) fn reliable_channel(&self, address: &Address) -> Arc<Mutex<Channel>> {
+        self.reliable.channel(address)
+    }
+
+    fn add_resp_callback(&self, msg_id: Uuid, sender: Sender<Arc<Message>>) {
+        if let Some(_) = self.resp_callbacks.write().unwrap().insert(msg_id, sender) {
+            println!("[MessageDispatcher]: overrides a resp_callback!");
         }
     }
 
@@ -58,67 +424,219 @@ impl MessageDispatcher {
     }
 
     fn handle_in_message(&self, msg: Arc<Message>) {
+        let span = tracing::debug_span!(
+            "hover.receive",
+            msg_type = ?msg.msg_type,
+            sender = %msg.sender_id,
+            traceparent = msg.header(TRACEPARENT).unwrap_or("")
+        );
+        let _span = span.enter();
+
         match msg.msg_type {
             MessageType::Request => self.handle_request(msg),
             MessageType::Response => self.handle_response(msg),
-            MessageType::Probe => self.send_event(self.build_probe_in_event(msg)),
-            MessageType::ProbeReq => self.send_event(self.build_probe_req_in_event(msg)),
-            MessageType::Broadcast => self.send_event(self.build_broadcast_in_event(msg)),
+            MessageType::ErrorResponse => self.handle_response(msg),
+            MessageType::Probe => self.handle_probe(msg),
+            MessageType::ProbeReq => self.handle_probe_req(msg),
+            MessageType::Broadcast => self.handle_broadcast(msg),
+            MessageType::Rpc => self.handle_rpc(msg),
+            MessageType::StreamChunk => self.handle_stream_chunk(msg),
+            MessageType::Reliable => self.handle_reliable(msg),
+        }
+    }
+
+    /**Delivers the message to the message listeners unless it is a duplicate.
+    Listener queues keep the order of the channel. The message is acknowledged only
+    if a listener queue took it, otherwise the sender is told to send it again*/
+    fn handle_reliable(&self, msg: Arc<Message>) {
+        match serialize::from_bytes::<ReliableMessage>(msg.payload.as_slice()) {
+            Ok(reliable) => {
+                let ack = self.reliable.accept(&msg.return_address, &reliable, || {
+                    let delivered = Arc::new(Message {
+                        cor_id: msg.cor_id,
+                        priority: msg.priority,
+                        sender_id: msg.sender_id,
+                        headers: msg.headers.clone(),
+                        timeout_ms: None,
+                        received_at: msg.received_at,
+                        msg_type: MessageType::Reliable,
+                        payload: reliable.payload.clone(),
+                        return_address: msg.return_address.clone(),
+                    });
+                    // every listener gets the message, not only the first one taking it
+                    let mut taken = false;
+                    for listener in self.listeners.snapshot().iter() {
+                        taken |= self.event_loop.dispatch(listener, delivered.clone());
+                    }
+                    taken
+                });
+
+                let response = Message {
+                    cor_id: msg.cor_id,
+                    return_address: self.local_node.addr.clone(),
+                    priority: msg.priority,
+                    sender_id: self.local_node.id,
+                    headers: BTreeMap::new(),
+                    timeout_ms: None,
+                    received_at: None,
+                    msg_type: MessageType::Response,
+                    payload: serialize::to_bytes(&ack).unwrap(),
+                };
+                if let Err(_) = self.outbox.post(
+                    response.priority,
+                    serialize::to_frame(self.codec, &response).unwrap(),
+                    &msg.return_address,
+                ) {
+                    eprintln!("[MessageDispatcher]: Error while sending reliable ack!");
+                }
+            }
+            Err(_) => eprintln!("[MessageDispatcher]: Error while reading reliable message"),
+        }
+    }
+
+    fn handle_stream_chunk(&self, msg: Arc<Message>) {
+        match serialize::from_bytes::<StreamChunk>(msg.payload.as_slice()) {
+            Ok(chunk) => {
+                let ack = self.streams.accept(&msg.return_address, chunk);
+                let reply = Message {
+                    cor_id: msg.cor_id,
+                    return_address: self.local_node.addr.clone(),
+                    priority: msg.priority,
+                    sender_id: self.local_node.id,
+                    headers: BTreeMap::new(),
+                    timeout_ms: None,
+                    received_at: None,
+                    msg_type: MessageType::Response,
+                    payload: serialize::to_bytes(&ack).unwrap(),
+                };
+
+                if let Err(_) = self.outbox.post(
+                    reply.priority,
+                    serialize::to_frame(self.codec, &reply).unwrap(),
+                    &msg.return_address,
+                ) {
+                    eprintln!("[MessageDispatcher]: Error while sending stream ack!");
+                }
+            }
+            Err(_) => eprintln!("[MessageDispatcher]: Error while reading stream chunk"),
+        }
+    }
+
+    fn handle_rpc(&self, msg: Arc<Message>) {
+        match serialize::from_bytes::<RpcRequest>(msg.payload.as_slice()) {
+            Ok(request) => {
+                let call = RpcCall {
+                    cor_id: msg.cor_id,
+                    return_address: msg.return_address.clone(),
+                    deadline: msg.deadline(),
+                    trace: TraceContext::from_message(&msg),
+                    payload: request.payload,
+                };
+
+                match self.handlers.read().unwrap().get(&request.method) {
+                    Some(handler) => {
+                        self.event_loop.dispatch(handler, call);
+                    }
+                    None => reply_rpc(
+                        &self.local_node,
+                        self.codec,
+                        &self.outbox,
+                        &call,
+                        Err(RpcError::UnknownMethod(request.method)),
+                    ),
+                }
+            }
+            Err(_) => eprintln!("[MessageDispatcher]: Error while reading rpc request"),
+        }
+    }
+
+    fn handle_probe(&self, msg: Arc<Message>) {
+        match serialize::from_bytes::<ProbePayload>(msg.payload.as_slice()) {
+            Ok(payload) => {
+                self.send_updates_event(payload.updates);
+                self.send_event(self.build_probe_in_event(msg));
+            }
+            Err(_) => eprintln!("[MessageDispatcher]: Error while reading probe payload"),
+        }
+    }
+
+    fn handle_probe_req(&self, msg: Arc<Message>) {
+        match serialize::from_bytes::<ProbeReqPayload>(msg.payload.as_slice()) {
+            Ok(payload) => {
+                self.send_updates_event(payload.updates);
+                self.send_event(self.build_probe_req_in_event(&msg, payload.node));
+            }
+            Err(_) => eprintln!("[MessageDispatcher]: Error while reading probe request payload"),
+        }
+    }
+
+    fn handle_broadcast(&self, msg: Arc<Message>) {
+        match serialize::from_bytes::<GossipMessage>(msg.payload.as_slice()) {
+            Ok(gossip) => {
+                self.send_updates_event(gossip.updates);
+                self.send_event(BroadcastIn {
+                    payload: gossip.broadcast,
+                });
+            }
+            Err(_) => eprintln!("[MessageDispatcher]: Error while reading gossip message"),
         }
     }
 
     fn handle_request(&self, msg: Arc<Message>) {
-        for listener in self.listeners.iter() {
-            listener(msg.clone())
+        let event_loop = &self.event_loop;
+        for listener in self.listeners.snapshot().iter() {
+            event_loop.dispatch(listener, msg.clone());
+        }
+
+        if let Some(ref handler) = *self.request_handler.read().unwrap() {
+            let request = Request {
+                msg,
+                local_node: self.local_node.clone(),
+                codec: self.codec,
+                outbox: self.outbox.clone(),
+                responded: false,
+            };
+            event_loop.dispatch(handler, request);
         }
     }
 
     fn handle_response(&self, msg: Arc<Message>) {
-        match self.resp_callbacks.read().unwrap().get(&msg.cor_id) {
-            Some(sender) => {
-                sender.send(msg);
-            }
-            None => {}
+        if let Some(sender) = self.resp_callbacks.read().unwrap().get(&msg.cor_id) {
+            // a duplicate response must not hold up the event loop
+            let _ = sender.try_send(msg);
         }
     }
 
     fn send_event(&self, event: Event) {
-        self.event_loop.read().unwrap().post_event(event);
+        self.event_loop.post_event(event);
     }
 
     fn build_probe_in_event(&self, msg: Arc<Message>) -> Event {
         ProbeIn {
-            cor_id: msg.cor_id.clone(),
+            cor_id: msg.cor_id,
             return_address: msg.return_address.clone(),
         }
     }
 
-    fn build_probe_req_in_event(&self, msg: Arc<Message>) -> Event {
-        let probe_payload: ProbeReqPayload =
-            serialize::from_bytes(msg.payload.clone().as_slice()).unwrap();
-
+    fn build_probe_req_in_event(&self, msg: &Message, probe_node: NodeMeta) -> Event {
         ProbeReqIn {
-            cor_id: msg.cor_id.clone(),
-            probe_node: probe_payload.node,
+            cor_id: msg.cor_id,
+            probe_node,
             return_address: msg.return_address.clone(),
         }
     }
 
-    fn build_broadcast_in_event(&self, msg: Arc<Message>) -> Event {
-        let broadcast_payload: BroadcastMessage =
-            serialize::from_bytes(msg.payload.clone().as_slice()).unwrap();
-
-        BroadcastIn {
-            payload: broadcast_payload,
+    fn send_updates_event(&self, updates: Vec<MembershipUpdate>) {
+        if !updates.is_empty() {
+            self.send_event(MembershipUpdatesIn { updates });
         }
     }
 }
 
 impl EventListener for MessageDispatcher {
     fn on_event(&self, event: Event) {
-        match event {
-            Event::MessageIn { msg } => self.handle_in_message(msg),
-            _ => {}
+        if let Event::MessageIn { msg } = event {
+            self.handle_in_message(msg)
         }
     }
 }
@@ -126,46 +644,96 @@ impl EventListener for MessageDispatcher {
 /**Service for sending messages across cluster.*/
 pub struct MessagingService {
     local_node: NodeMeta,
+    codec: CodecKind,
     message_dispatcher: Arc<RwLock<MessageDispatcher>>,
-    event_loop: Arc<RwLock<EventLoop>>,
+    membership_service: RwLock<Weak<RwLock<MembershipService>>>,
+    retry: RetryPolicy,
+    reliable_retry: RetryPolicy,
+    reliable_ack_timeout: Duration,
+    breakers: Arc<RwLock<CircuitBreakers>>,
+    stream_config: StreamConfig,
+    outbox: Arc<Outbox>,
+    event_loop: Arc<EventLoop>,
 }
 
 impl MessagingService {
-    pub fn new(
+    pub(crate) fn new(
         local_node: NodeMeta,
+        codec: CodecKind,
+        config: MessagingConfig,
+        stream_config: StreamConfig,
         message_dispatcher: Arc<RwLock<MessageDispatcher>>,
-        event_loop: Arc<RwLock<EventLoop>>,
+        event_loop: Arc<EventLoop>,
     ) -> MessagingService {
+        let breakers = Arc::new(RwLock::new(CircuitBreakers::new(&config)));
+        event_loop.add_listener(breakers.clone()).detach();
+        let outbox = message_dispatcher.read().unwrap().outbox();
+
         MessagingService {
             local_node,
+            codec,
             message_dispatcher,
+            membership_service: RwLock::new(Weak::new()),
+            retry: RetryPolicy::from_config(&config),
+            reliable_retry: RetryPolicy {
+                max_attempts: config.reliable_max_attempts.max(1),
+                ..RetryPolicy::from_config(&config)
+            },
+            reliable_ack_timeout: Duration::from_millis(config.reliable_ack_timeout_ms),
+            breakers,
+            stream_config,
+            outbox,
             event_loop,
         }
     }
 
-    /**public*/
+    /**Membership service is created after the messaging service, so it is bound later*/
+    pub(crate) fn bind_membership_service(
+        &self,
+        membership_service: &Arc<RwLock<MembershipService>>,
+    ) {
+        *self.membership_service.write().unwrap() = Arc::downgrade(membership_service);
+    }
+
+    /**Replies to a protocol message*/
     pub fn reply(
         &self,
         msg_id: Uuid,
         payload: Vec<u8>,
         address: Address,
-    ) -> Result<(), Box<Error>> {
-        let msg = Message {
-            cor_id: msg_id,
-            return_address: self.local_node.addr.clone(),
-            msg_type: MessageType::Response,
-            payload,
-        };
-        let mut msg_bytes = serialize::to_bytes(&msg).unwrap();
+    ) -> Result<(), Box<dyn Error>> {
+        let mut msg = self.new_message(MessageType::Response, payload);
+        msg.cor_id = msg_id;
+        msg.priority = Priority::Protocol;
+        let msg_bytes = serialize::to_frame(self.codec, &msg).unwrap();
 
-        self.do_send(msg_bytes, &address)?;
+        self.do_send(msg.priority, msg_bytes, &address)?;
 
         Ok(())
     }
 
+    /**Options used by the user sends that do not take them explicitly*/
+    pub fn default_send_options(&self) -> SendOptions {
+        SendOptions {
+            retry: self.retry.clone(),
+            idempotent: false,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    pub fn circuit_breakers(&self) -> Arc<RwLock<CircuitBreakers>> {
+        self.breakers.clone()
+    }
+
     /**public*/
-    pub fn send_to_address(&self, payload: Vec<u8>, address: Address) -> Result<(), Box<Error>> {
-        self.send_to_address_type(payload, address, MessageType::Request)
+    pub fn send_to_address(
+        &self,
+        payload: Vec<u8>,
+        address: Address,
+    ) -> Result<(), Box<dyn Error>> {
+        self.with_retries(&address, &self.default_send_options(), || {
+            self.send_to_address_type(payload.clone(), address.clone(), MessageType::Request)
+        })
     }
 
     /**public*/
@@ -174,43 +742,229 @@ impl MessagingService {
         payload: Vec<u8>,
         address: Address,
         msg_type: MessageType,
-    ) -> Result<(), Box<Error>> {
-        let msg = Message {
+    ) -> Result<(), Box<dyn Error>> {
+        self.send_message(self.new_message(msg_type, payload), &address)
+    }
+
+    /**New message of this node. Headers can be added before it is sent*/
+    pub fn new_message(&self, msg_type: MessageType, payload: Vec<u8>) -> Message {
+        let mut headers = BTreeMap::new();
+        headers.insert(
+            TRACEPARENT.to_string(),
+            trace::next_context().to_traceparent(),
+        );
+
+        Message {
             cor_id: gen_msg_id(),
             return_address: self.local_node.addr.clone(),
+            priority: Priority::of(&msg_type),
+            sender_id: self.local_node.id,
+            headers,
+            timeout_ms: None,
+            received_at: None,
             msg_type,
             payload,
-        };
-        let mut msg_bytes = serialize::to_bytes(&msg).unwrap();
+        }
+    }
 
-        self.do_send(msg_bytes, &address)?;
+    /**Sends the message as is. No retries*/
+    pub fn send_message(&self, msg: Message, address: &Address) -> Result<(), Box<dyn Error>> {
+        let span = tracing::debug_span!(
+            "hover.send",
+            msg_type = ?msg.msg_type,
+            address = ?address,
+            traceparent = msg.header(TRACEPARENT).unwrap_or("")
+        );
+        let _span = span.enter();
+        let msg_bytes = serialize::to_frame(self.codec, &msg)?;
 
-        Ok(())
+        self.do_send(msg.priority, msg_bytes, address)
+    }
+
+    /**Sends the message and waits for the response. The timeout is propagated
+    to the receiver, see Message::deadline. No retries*/
+    pub fn send_message_receive(
+        &self,
+        mut msg: Message,
+        address: &Address,
+        timeout: Duration,
+    ) -> Result<Arc<Message>, Box<dyn Error>> {
+        msg.timeout_ms = Some(to_millis(timeout));
+        let span = tracing::debug_span!(
+            "hover.send",
+            msg_type = ?msg.msg_type,
+            address = ?address,
+            traceparent = msg.header(TRACEPARENT).unwrap_or("")
+        );
+        let _span = span.enter();
+        let msg_bytes = serialize::to_frame(self.codec, &msg)?;
+
+        self.do_send_receive(msg.cor_id, msg.priority, msg_bytes, address, timeout)
     }
 
     /**public*/
-    pub fn send_to_member(&self, payload: Vec<u8>, member: &NodeMeta) -> Result<(), Box<Error>> {
-        self.send_to_member_type(payload, member, MessageType::Request)
+    pub fn send_to_member(
+        &self,
+        payload: Vec<u8>,
+        member: &NodeMeta,
+    ) -> Result<(), Box<dyn Error>> {
+        self.send_to_member_with(payload, member, &self.default_send_options())
     }
 
     /**public*/
-    pub fn send_to_member_type(
+    pub fn send_to_member_with(
         &self,
         payload: Vec<u8>,
         member: &NodeMeta,
-        msg_type: MessageType,
-    ) -> Result<(), Box<Error>> {
-        let msg = Message {
-            cor_id: gen_msg_id(),
-            return_address: self.local_node.addr.clone(),
-            msg_type,
-            payload,
+        options: &SendOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        self.with_retries(&member.addr, options, || {
+            let mut msg = self.new_message(MessageType::Request, payload.clone());
+            msg.headers.extend(options.headers.clone());
+            self.send_message(msg, &member.addr)
+        })
+    }
+
+    /**Sends to the member with the id at its current address. Fails with
+    UnknownMember if no alive member has the id*/
+    pub fn send_to_id(&self, id: Uuid, payload: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.send_to_id_with(id, payload, &self.default_send_options())
+    }
+
+    /**public*/
+    pub fn send_to_id_with(
+        &self,
+        id: Uuid,
+        payload: Vec<u8>,
+        options: &SendOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let resolve = || self.resolve_member(id).map(|member| member.addr);
+        self.with_retries_resolving(resolve, options, |address| {
+            let mut msg = self.new_message(MessageType::Request, payload.clone());
+            msg.headers.extend(options.headers.clone());
+            self.send_message(msg, address)
+        })
+    }
+
+    /**Sends the request to the member with the id at its current address and
+    waits for the response. Fails with UnknownMember if no alive member has the id*/
+    pub fn request_to_id(
+        &self,
+        id: Uuid,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Arc<Message>, Box<dyn Error>> {
+        self.request_to_id_with(id, payload, timeout, &self.default_send_options())
+    }
+
+    /**public*/
+    pub fn request_to_id_with(
+        &self,
+        id: Uuid,
+        payload: Vec<u8>,
+        timeout: Duration,
+        options: &SendOptions,
+    ) -> Result<Arc<Message>, Box<dyn Error>> {
+        let resolve = || self.resolve_member(id).map(|member| member.addr);
+        self.with_retries_resolving(resolve, options, |address| {
+            let mut msg = self.new_message(MessageType::Request, payload.clone());
+            msg.headers.extend(options.headers.clone());
+            self.send_message_receive(msg, address, timeout)
+        })
+    }
+
+    /**Current address of the member. Only alive members are resolved, a suspected
+    one fails like an unknown one. The local node resolves to itself*/
+    fn resolve_member(&self, id: Uuid) -> Result<NodeMeta, Box<dyn Error>> {
+        if id == self.local_node.id {
+            return Ok(self.local_node.clone());
+        }
+
+        let member = match self.membership_service.read().unwrap().upgrade() {
+            Some(ms) => ms.read().unwrap().alive_member(&id),
+            None => return Err(Box::from("Membership service is not available!")),
         };
-        let mut msg_bytes = serialize::to_bytes(&msg).unwrap();
 
-        self.do_send(msg_bytes, &member.addr)?;
+        match member {
+            Some(node) => Ok(node),
+            None => Err(Box::new(UnknownMember { id })),
+        }
+    }
 
-        Ok(())
+    /**Sends the message over the reliable channel to the member. Returns once the
+    member acknowledged it, retransmitting it until then. Retransmissions are not
+    delivered twice and the member receives the messages of this node in the order
+    they were sent. Delivered messages have the Reliable type. Fails with NotDelivered
+    if no listener of the member took the message*/
+    pub fn send_reliable(&self, payload: Vec<u8>, member: &NodeMeta) -> Result<(), Box<dyn Error>> {
+        let channel = self
+            .message_dispatcher
+            .read()
+            .unwrap()
+            .reliable_channel(&member.addr);
+        let mut channel = channel.lock().unwrap();
+
+        let options = SendOptions {
+            retry: self.reliable_retry.clone(),
+            idempotent: true,
+            headers: BTreeMap::new(),
+        };
+
+        // a gap reported by the receiver is resolved by resending on a new channel
+        for _ in 0..2 {
+            let bytes = serialize::to_bytes(&ReliableMessage {
+                channel: channel.id,
+                seq: channel.next_seq,
+                payload: payload.clone(),
+            })?;
+            let result = self.with_retries(&member.addr, &options, || {
+                self.send_to_member_receive_type(
+                    bytes.clone(),
+                    member,
+                    MessageType::Reliable,
+                    self.reliable_ack_timeout,
+                )
+            });
+
+            let response = match result {
+                Ok(response) => response,
+                Err(err) => {
+                    // the message may have been delivered with only the ack lost. If it
+                    // was not, the receiver reports the gap on the next message
+                    channel.next_seq += 1;
+                    return Err(err);
+                }
+            };
+
+            match serialize::from_bytes::<ReliableAck>(response.payload.as_slice())? {
+                ReliableAck::Delivered => {
+                    channel.next_seq += 1;
+                    return Ok(());
+                }
+                ReliableAck::OutOfOrder => channel.reset(),
+                ack => {
+                    return Err(Box::new(NotDelivered {
+                        address: member.addr.clone(),
+                        ack,
+                    }))
+                }
+            }
+        }
+
+        Err(Box::new(NotDelivered {
+            address: member.addr.clone(),
+            ack: ReliableAck::OutOfOrder,
+        }))
+    }
+
+    /**public*/
+    pub fn send_to_member_type(
+        &self,
+        payload: Vec<u8>,
+        member: &NodeMeta,
+        msg_type: MessageType,
+    ) -> Result<(), Box<dyn Error>> {
+        self.send_message(self.new_message(msg_type, payload), &member.addr)
     }
 
     /**public*/
@@ -219,8 +973,15 @@ impl MessagingService {
         payload: Vec<u8>,
         address: Address,
         timeout: Duration,
-    ) -> Result<Arc<Message>, Box<Error>> {
-        self.send_to_address_receive_type(payload, address, MessageType::Request, timeout)
+    ) -> Result<Arc<Message>, Box<dyn Error>> {
+        self.with_retries(&address, &self.default_send_options(), || {
+            self.send_to_address_receive_type(
+                payload.clone(),
+                address.clone(),
+                MessageType::Request,
+                timeout,
+            )
+        })
     }
 
     /**public*/
@@ -230,17 +991,8 @@ impl MessagingService {
         address: Address,
         msg_type: MessageType,
         timeout: Duration,
-    ) -> Result<Arc<Message>, Box<Error>> {
-        let correlation_id = gen_msg_id();
-        let msg = Message {
-            cor_id: correlation_id,
-            return_address: self.local_node.addr.clone(),
-            msg_type,
-            payload,
-        };
-        let mut msg_bytes = serialize::to_bytes(&msg).unwrap();
-
-        self.do_send_receive(correlation_id, msg_bytes, &address, timeout)
+    ) -> Result<Arc<Message>, Box<dyn Error>> {
+        self.send_message_receive(self.new_message(msg_type, payload), &address, timeout)
     }
 
     /**public*/
@@ -249,8 +1001,23 @@ impl MessagingService {
         payload: Vec<u8>,
         member: &NodeMeta,
         timeout: Duration,
-    ) -> Result<Arc<Message>, Box<Error>> {
-        self.send_to_member_receive_type(payload, member, MessageType::Request, timeout)
+    ) -> Result<Arc<Message>, Box<dyn Error>> {
+        self.send_to_member_receive_with(payload, member, timeout, &self.default_send_options())
+    }
+
+    /**public*/
+    pub fn send_to_member_receive_with(
+        &self,
+        payload: Vec<u8>,
+        member: &NodeMeta,
+        timeout: Duration,
+        options: &SendOptions,
+    ) -> Result<Arc<Message>, Box<dyn Error>> {
+        self.with_retries(&member.addr, options, || {
+            let mut msg = self.new_message(MessageType::Request, payload.clone());
+            msg.headers.extend(options.headers.clone());
+            self.send_message_receive(msg, &member.addr, timeout)
+        })
     }
 
     /**public*/
@@ -260,85 +1027,833 @@ impl MessagingService {
         member: &NodeMeta,
         msg_type: MessageType,
         timeout: Duration,
-    ) -> Result<Arc<Message>, Box<Error>> {
-        let correlation_id = gen_msg_id();
-        let msg = Message {
-            cor_id: correlation_id,
-            return_address: self.local_node.addr.clone(),
-            msg_type,
-            payload,
+    ) -> Result<Arc<Message>, Box<dyn Error>> {
+        self.send_message_receive(self.new_message(msg_type, payload), &member.addr, timeout)
+    }
+
+    /**Registers a handler of the method. See MessageDispatcher::register_handler*/
+    pub fn register_handler<Req, Resp, F>(&self, method: &str, f: F) -> Subscription
+    where
+        F: Fn(Req) -> Result<Resp, Box<dyn Error>> + 'static + Send + Sync,
+        Req: DeserializeOwned,
+        Resp: Serialize,
+    {
+        self.message_dispatcher
+            .read()
+            .unwrap()
+            .register_handler(method, f)
+    }
+
+    /**Calls the method on the member and waits for its result*/
+    pub fn call<Req, Resp>(
+        &self,
+        member: &NodeMeta,
+        method: &str,
+        req: &Req,
+        timeout: Duration,
+    ) -> Result<Resp, RpcError>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        self.call_with(member, method, req, timeout, &self.default_send_options())
+    }
+
+    pub fn call_with<Req, Resp>(
+        &self,
+        member: &NodeMeta,
+        method: &str,
+        req: &Req,
+        timeout: Duration,
+        options: &SendOptions,
+    ) -> Result<Resp, RpcError>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let request = RpcRequest {
+            method: method.to_string(),
+            payload: serialize::to_bytes(req).map_err(|e| RpcError::Codec(e.to_string()))?,
         };
-        let mut msg_bytes = serialize::to_bytes(&msg).unwrap();
+        let bytes = serialize::to_bytes(&request).map_err(|e| RpcError::Codec(e.to_string()))?;
+
+        let msg = self
+            .with_retries(&member.addr, options, || {
+                let mut msg = self.new_message(MessageType::Rpc, bytes.clone());
+                msg.headers.extend(options.headers.clone());
+                self.send_message_receive(msg, &member.addr, timeout)
+            })
+            .map_err(to_rpc_error)?;
 
-        self.do_send_receive(correlation_id, msg_bytes, &member.addr, timeout)
+        let response = serialize::from_bytes::<RpcResponse>(msg.payload.as_slice())
+            .map_err(|e| RpcError::Codec(e.to_string()))?;
+        let payload = response.result?;
+
+        serialize::from_bytes::<Resp>(payload.as_slice())
+            .map_err(|e| RpcError::Codec(e.to_string()))
+    }
+
+    /**Sends the request to the members matching the filter at once. Responses are
+    collected as they arrive. Fails if the membership service is gone*/
+    pub fn query(
+        &self,
+        payload: Vec<u8>,
+        filter: QueryFilter,
+        timeout: Duration,
+    ) -> Result<QueryResponses, Box<dyn Error>> {
+        let members = match self.membership_service.read().unwrap().upgrade() {
+            Some(ms) => ms.read().unwrap().all_members(),
+            None => return Err(Box::from("Membership service is not available!")),
+        };
+        let targets = filter.select(members);
+
+        let (work_s, work_r) = crossbeam_channel::unbounded();
+        let mut pending = HashMap::new();
+        for member in targets.iter() {
+            let mut msg = self.new_message(MessageType::Request, payload.clone());
+            msg.timeout_ms = Some(to_millis(timeout));
+            work_s.send((
+                msg.cor_id,
+                member.clone(),
+                serialize::to_frame(self.codec, &msg)?,
+            ))?;
+            pending.insert(msg.cor_id, member.clone());
+        }
+        drop(work_s);
+
+        // every response and failure of the query goes through these channels
+        let (s, r) = crossbeam_channel::bounded(targets.len());
+        let (resp_s, resp_r) = crossbeam_channel::bounded(targets.len());
+        let (failed_s, failed_r) = crossbeam_channel::bounded(targets.len());
+
+        for _ in 0..targets.len().min(QUERY_SENDERS) {
+            let work_r_ = work_r.clone();
+            let dispatcher_ = self.message_dispatcher.clone();
+            let outbox_ = self.outbox.clone();
+            let breakers_ = self.breakers.clone();
+            let resp_s_ = resp_s.clone();
+            let failed_s_ = failed_s.clone();
+
+            std::thread::spawn(move || {
+                for (cor_id, member, bytes) in work_r_.iter() {
+                    let result = send_query_request(
+                        &dispatcher_,
+                        &outbox_,
+                        &breakers_,
+                        cor_id,
+                        &member,
+                        bytes,
+                        &resp_s_,
+                    );
+                    if let Err(err) = result {
+                        if let Err(_) = failed_s_.send((cor_id, to_rpc_error(err))) {
+                            eprintln!(
+                                "[MessageService]: Query finished before a failure was reported!"
+                            );
+                        }
+                    }
+                }
+            });
+        }
+
+        let dispatcher_ = self.message_dispatcher.clone();
+        let breakers_ = self.breakers.clone();
+        let deadline = Instant::now() + timeout;
+        std::thread::spawn(move || {
+            collect_query(
+                &dispatcher_,
+                &breakers_,
+                pending,
+                resp_r,
+                failed_r,
+                s,
+                deadline,
+            )
+        });
+
+        Ok(QueryResponses::new(r, targets.len(), timeout))
+    }
+
+    /**Streams the reader to the member in chunks. Only a window of chunks is held
+    by the receiver at a time. Returns the number of bytes sent. On failure the
+    transfer can be continued with resume_stream from the offset of the error*/
+    pub fn send_stream<R: Read>(&self, member: &NodeMeta, reader: R) -> Result<u64, StreamError> {
+        self.resume_stream(member, Uuid::new_v4(), 0, reader)
+    }
+
+    /**Continues the stream from the offset. Reader has to be positioned at the offset.
+    Returns the offset after the last byte*/
+    pub fn resume_stream<R: Read>(
+        &self,
+        member: &NodeMeta,
+        stream_id: Uuid,
+        offset: u64,
+        mut reader: R,
+    ) -> Result<u64, StreamError> {
+        let chunk_size = self.stream_config.chunk_size.max(1);
+        let mut offset = offset;
+
+        loop {
+            let data = read_chunk(&mut reader, chunk_size).map_err(|e| StreamError {
+                stream_id,
+                offset,
+                reason: e.to_string(),
+            })?;
+            let next_offset = offset + data.len() as u64;
+            let last = data.len() < chunk_size;
+
+            let chunk = StreamChunk {
+                stream_id,
+                offset,
+                data,
+                last,
+            };
+            self.send_chunk(member, &chunk, next_offset)?;
+
+            offset = next_offset;
+            if last {
+                return Ok(offset);
+            }
+        }
+    }
+
+    /**Sends the chunk until the receiver takes it, waiting while its window is full*/
+    fn send_chunk(
+        &self,
+        member: &NodeMeta,
+        chunk: &StreamChunk,
+        next_offset: u64,
+    ) -> Result<(), StreamError> {
+        let stream_error = |offset: u64, reason: String| StreamError {
+            stream_id: chunk.stream_id,
+            offset,
+            reason,
+        };
+        let bytes =
+            serialize::to_bytes(chunk).map_err(|e| stream_error(chunk.offset, e.to_string()))?;
+        let options = SendOptions {
+            retry: self.retry.clone(),
+            idempotent: true,
+            headers: BTreeMap::new(),
+        };
+        let ack_timeout = Duration::from_millis(self.stream_config.ack_timeout_ms);
+        let idle_timeout = Duration::from_millis(self.stream_config.idle_timeout_ms);
+        let started = Instant::now();
+        let mut delay = Duration::from_millis(10);
+
+        loop {
+            let msg = self
+                .with_retries(&member.addr, &options, || {
+                    self.send_to_member_receive_type(
+                        bytes.clone(),
+                        member,
+                        MessageType::StreamChunk,
+                        ack_timeout,
+                    )
+                })
+                .map_err(|e| stream_error(chunk.offset, e.to_string()))?;
+            let ack = serialize::from_bytes::<StreamAck>(msg.payload.as_slice())
+                .map_err(|e| stream_error(chunk.offset, e.to_string()))?;
+
+            if ack.closed {
+                return Err(stream_error(
+                    ack.offset,
+                    String::from("Stream was closed by the receiver"),
+                ));
+            }
+
+            if !ack.busy {
+                return match ack.offset == next_offset {
+                    true => Ok(()),
+                    false => Err(stream_error(
+                        ack.offset,
+                        String::from("Receiver expects another offset"),
+                    )),
+                };
+            }
+
+            if started.elapsed() > idle_timeout {
+                return Err(stream_error(
+                    chunk.offset,
+                    String::from("Receiver does not consume the stream"),
+                ));
+            }
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(Duration::from_millis(500));
+        }
     }
 
     /**public*/
-    pub fn broadcast(&self, bytes: Vec<u8>) -> Result<(), Box<Error>> {
-        let event = Event::BroadcastOut { payload: bytes };
+    pub fn broadcast(&self, bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let event = Event::BroadcastOut {
+            payload: bytes,
+            traceparent: Some(trace::next_context().to_traceparent()),
+        };
 
-        self.event_loop.read().unwrap().post_event(event)
+        self.event_loop.post_event(event);
+        Ok(())
     }
 
-    fn do_send(&self, mut bytes: Vec<u8>, addr: &Address) -> Result<(), Box<Error>> {
-        match TcpStream::connect((addr.ip, addr.port)) {
-            Ok(mut stream) => match stream.write_all(bytes.as_mut_slice()) {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
-            },
-            Err(err) => Err(Box::new(err)),
+    fn do_send(
+        &self,
+        priority: Priority,
+        bytes: Vec<u8>,
+        addr: &Address,
+    ) -> Result<(), Box<dyn Error>> {
+        self.outbox.send(priority, bytes, addr)
+    }
+
+    /**Runs a user send guarded by the circuit breaker of the address, retrying it
+    as allowed by the options. The breakers are locked only to check and record,
+    never while sending or backing off*/
+    fn with_retries<T, F>(
+        &self,
+        address: &Address,
+        options: &SendOptions,
+        f: F,
+    ) -> Result<T, Box<dyn Error>>
+    where
+        F: Fn() -> Result<T, Box<dyn Error>>,
+    {
+        self.with_retries_resolving(|| Ok(address.clone()), options, |_| f())
+    }
+
+    /**Like with_retries, but the address is resolved before every attempt, so a retry
+    reaches a member that came back at another address. Resolving failures are
+    retried as well, nothing was sent*/
+    fn with_retries_resolving<T, R, F>(
+        &self,
+        resolve: R,
+        options: &SendOptions,
+        f: F,
+    ) -> Result<T, Box<dyn Error>>
+    where
+        R: Fn() -> Result<Address, Box<dyn Error>>,
+        F: Fn(&Address) -> Result<T, Box<dyn Error>>,
+    {
+        let mut attempt = 1;
+
+        loop {
+            let result = match resolve() {
+                Ok(address) => {
+                    if !self.breakers.read().unwrap().allow(&address) {
+                        return Err(Box::new(CircuitOpenError { address }));
+                    }
+
+                    let result = f(&address);
+                    record_result(&self.breakers.read().unwrap(), &address, &result);
+                    result
+                }
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Err(ref err)
+                    if !err.is::<RpcError>()
+                        && options.retry.should_retry(
+                            err.as_ref(),
+                            options.idempotent,
+                            attempt,
+                        ) => {}
+                _ => return result,
+            }
+
+            std::thread::sleep(options.retry.backoff(attempt));
+            attempt += 1;
         }
     }
 
     fn do_send_receive(
         &self,
         correlation_id: Uuid,
-        mut bytes: Vec<u8>,
+        priority: Priority,
+        bytes: Vec<u8>,
         addr: &Address,
         timeout: Duration,
-    ) -> Result<Arc<Message>, Box<Error>> {
-        //create channel between receiver and current thread
-        let (s, r): (Sender<Arc<Message>>, Receiver<Arc<Message>>) = crossbeam_channel::bounded(1);
+    ) -> Result<Arc<Message>, Box<dyn Error>> {
+        send_receive(
+            &self.message_dispatcher,
+            &self.outbox,
+            correlation_id,
+            priority,
+            bytes,
+            addr,
+            timeout,
+        )
+    }
+}
 
-        //add message listener for given correlation id
-        self.message_dispatcher
+fn gen_msg_id() -> Uuid {
+    Uuid::new_v4()
+}
+
+fn to_millis(duration: Duration) -> u64 {
+    duration.as_millis().min(u128::from(u64::MAX)) as u64
+}
+
+fn to_rpc_error(err: Box<dyn Error>) -> RpcError {
+    if let Some(rpc_error) = err.downcast_ref::<RpcError>() {
+        return rpc_error.clone();
+    }
+
+    match err.downcast_ref::<RecvTimeoutError>() {
+        Some(_) => RpcError::Timeout,
+        None => RpcError::Transport(err.to_string()),
+    }
+}
+
+fn send_receive(
+    message_dispatcher: &RwLock<MessageDispatcher>,
+    outbox: &Outbox,
+    correlation_id: Uuid,
+    priority: Priority,
+    bytes: Vec<u8>,
+    addr: &Address,
+    timeout: Duration,
+) -> Result<Arc<Message>, Box<dyn Error>> {
+    //create channel between receiver and current thread
+    let (s, r): (Sender<Arc<Message>>, Receiver<Arc<Message>>) = crossbeam_channel::bounded(1);
+
+    //add message listener for given correlation id
+    message_dispatcher
+        .read()
+        .unwrap()
+        .add_resp_callback(correlation_id, s);
+
+    if let Err(err) = outbox.send(priority, bytes, addr) {
+        eprintln!("[MessageSercive]: Error while sending a message!");
+        message_dispatcher
             .read()
             .unwrap()
-            .add_resp_callback(correlation_id, s);
+            .remove_resp_callback(correlation_id);
+        return Err(err);
+    }
 
-        match self.do_send(bytes, &addr) {
-            Err(err) => {
-                eprintln!("[MessageSercive]: Error while sending a message!");
-                self.message_dispatcher
-                    .read()
-                    .unwrap()
-                    .remove_resp_callback(correlation_id);
-                return Err(err);
+    //block until received response
+    match r.recv_timeout(timeout) {
+        Ok(response) => {
+            message_dispatcher
+                .read()
+                .unwrap()
+                .remove_resp_callback(correlation_id);
+
+            match response.msg_type {
+                MessageType::ErrorResponse => Err(Box::new(serialize::from_bytes::<RpcError>(
+                    response.payload.as_slice(),
+                )?)),
+                _ => Ok(response),
             }
-            Ok(_) => {}
         }
+        Err(err) => {
+            eprintln!("[MessageService]: Error while waiting for the response!");
+            message_dispatcher
+                .read()
+                .unwrap()
+                .remove_resp_callback(correlation_id);
+            Err(Box::new(err))
+        }
+    }
+}
+
+/**A response continues the trace of its request*/
+fn response_headers(trace: Option<TraceContext>) -> BTreeMap<String, String> {
+    let mut headers = BTreeMap::new();
+    if let Some(context) = trace {
+        headers.insert(TRACEPARENT.to_string(), context.child().to_traceparent());
+    }
+    headers
+}
+
+fn send_bytes(mut bytes: Vec<u8>, addr: &Address) -> Result<(), Box<dyn Error + Send>> {
+    let socket_addr = SocketAddr::from((addr.ip, addr.port));
+    match TcpStream::connect_timeout(&socket_addr, SEND_TIMEOUT) {
+        Ok(mut stream) => match stream
+            .set_write_timeout(Some(SEND_TIMEOUT))
+            .and_then(|_| stream.write_all(bytes.as_mut_slice()))
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Box::new(err)),
+        },
+        Err(err) => Err(Box::new(ConnectError(err))),
+    }
+}
 
-        //block until received response
-        return match r.recv_timeout(timeout) {
-            Ok(response) => {
-                self.message_dispatcher
+/**Sends one request of a query. Its response is received through the shared channel*/
+fn send_query_request(
+    message_dispatcher: &RwLock<MessageDispatcher>,
+    outbox: &Outbox,
+    breakers: &RwLock<CircuitBreakers>,
+    cor_id: Uuid,
+    member: &NodeMeta,
+    bytes: Vec<u8>,
+    responses: &Sender<Arc<Message>>,
+) -> Result<(), Box<dyn Error>> {
+    if !breakers.read().unwrap().allow(&member.addr) {
+        return Err(Box::new(CircuitOpenError {
+            address: member.addr.clone(),
+        }));
+    }
+
+    message_dispatcher
+        .read()
+        .unwrap()
+        .add_resp_callback(cor_id, responses.clone());
+
+    let result = outbox.send(Priority::of(&MessageType::Request), bytes, &member.addr);
+    if result.is_err() {
+        message_dispatcher
+            .read()
+            .unwrap()
+            .remove_resp_callback(cor_id);
+        breakers.read().unwrap().record_failure(&member.addr);
+    }
+    result
+}
+
+/**Turns the responses and failures of a query into QueryResponses until every member
+is done or the deadline passed. Members that did not answer count as failures*/
+fn collect_query(
+    message_dispatcher: &RwLock<MessageDispatcher>,
+    breakers: &RwLock<CircuitBreakers>,
+    mut pending: HashMap<Uuid, NodeMeta>,
+    mut responses: Receiver<Arc<Message>>,
+    mut failures: Receiver<(Uuid, RpcError)>,
+    sender: Sender<QueryResponse>,
+    deadline: Instant,
+) {
+    while !pending.is_empty() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        let (cor_id, result, answered) = select! {
+            recv(responses) -> msg => match msg {
+                Ok(msg) => {
+                    let result = match msg.msg_type {
+                        MessageType::ErrorResponse => {
+                            Err(serialize::from_bytes::<RpcError>(msg.payload.as_slice())
+                                .unwrap_or_else(|e| RpcError::Codec(e.to_string())))
+                        }
+                        _ => Ok(msg.clone()),
+                    };
+                    (msg.cor_id, result, true)
+                }
+                Err(_) => {
+                    // nothing is waiting for a response anymore
+                    responses = crossbeam_channel::never();
+                    continue;
+                }
+            },
+            recv(failures) -> failure => match failure {
+                Ok((cor_id, err)) => (cor_id, Err(err), false),
+                Err(_) => {
+                    // every request is sent
+                    failures = crossbeam_channel::never();
+                    continue;
+                }
+            },
+            default(deadline - now) => break,
+        };
+
+        if let Some(member) = pending.remove(&cor_id) {
+            if answered {
+                message_dispatcher
                     .read()
                     .unwrap()
-                    .remove_resp_callback(correlation_id);
-                Ok(response)
+                    .remove_resp_callback(cor_id);
+                // error responses mean the member is reachable as well
+                breakers.read().unwrap().record_success(&member.addr);
             }
-            Err(err) => {
-                eprintln!("[MessageService]: Error while waiting for the response!");
-                self.message_dispatcher
-                    .read()
-                    .unwrap()
-                    .remove_resp_callback(correlation_id);
-                Err(Box::new(err))
+            if let Err(_) = sender.send(QueryResponse { member, result }) {
+                eprintln!("[MessageService]: Query responses are not read anymore!");
             }
-        };
+        }
+    }
+
+    for (cor_id, member) in pending {
+        message_dispatcher
+            .read()
+            .unwrap()
+            .remove_resp_callback(cor_id);
+        breakers.read().unwrap().record_failure(&member.addr);
     }
 }
 
-fn gen_msg_id() -> Uuid {
-    Uuid::new_v4()
+/**Connection errors and timeouts count as failures of the member. An RpcError, which
+includes error responses sent by its handlers, means the member answered, so it counts
+as a success*/
+fn record_result<T>(
+    breakers: &CircuitBreakers,
+    address: &Address,
+    result: &Result<T, Box<dyn Error>>,
+) {
+    match result {
+        Err(ref err) if !err.is::<RpcError>() => breakers.record_failure(address),
+        _ => breakers.record_success(address),
+    }
+}
+
+/**Queues the response of the call without waiting for it to be sent, so it does not
+hold up the event loop*/
+fn reply_rpc(
+    local_node: &NodeMeta,
+    codec: CodecKind,
+    outbox: &Outbox,
+    call: &RpcCall,
+    result: Result<Vec<u8>, RpcError>,
+) {
+    let msg = Message {
+        cor_id: call.cor_id,
+        return_address: local_node.addr.clone(),
+        priority: Priority::User,
+        sender_id: local_node.id,
+        headers: response_headers(call.trace),
+        timeout_ms: None,
+        received_at: None,
+        msg_type: MessageType::Response,
+        payload: serialize::to_bytes(&RpcResponse { result }).unwrap(),
+    };
+    let msg_bytes = serialize::to_frame(codec, &msg).unwrap();
+
+    if let Err(_) = outbox.post(msg.priority, msg_bytes, &call.return_address) {
+        eprintln!("[MessageDispatcher]: Error while sending rpc response!");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::MemberStatus;
+    use crate::config::HoverConfig;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    /**Dispatcher of a node whose responses go to the returned listener*/
+    fn dispatcher() -> (MessageDispatcher, TcpListener) {
+        let config = HoverConfig::default().unwrap();
+        let event_loop = Arc::new(EventLoop::new(
+            config.listeners.clone(),
+            config.subscriptions.clone(),
+            config.lanes.clone(),
+        ));
+        let local_node = NodeMeta {
+            id: Uuid::new_v4(),
+            addr: Address {
+                ip: Ipv4Addr::LOCALHOST,
+                port: 7000,
+            },
+        };
+        let dispatcher = MessageDispatcher::new(
+            local_node,
+            config.codec,
+            config.streams,
+            config.lanes,
+            event_loop,
+        );
+
+        (
+            dispatcher,
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        )
+    }
+
+    fn rpc(caller: &TcpListener, arg: &str, received_ago: Duration, timeout_ms: u64) -> Message {
+        let request = RpcRequest {
+            method: String::from("echo"),
+            payload: serialize::to_bytes(arg).unwrap(),
+        };
+
+        Message {
+            cor_id: Uuid::new_v4(),
+            msg_type: MessageType::Rpc,
+            priority: Priority::User,
+            sender_id: Uuid::new_v4(),
+            headers: BTreeMap::new(),
+            timeout_ms: Some(timeout_ms),
+            received_at: Some(Instant::now() - received_ago),
+            payload: serialize::to_bytes(&request).unwrap(),
+            return_address: Address {
+                ip: Ipv4Addr::LOCALHOST,
+                port: caller.local_addr().unwrap().port(),
+            },
+        }
+    }
+
+    fn service(dispatcher: MessageDispatcher) -> MessagingService {
+        let config = HoverConfig::default().unwrap();
+        let event_loop = dispatcher.event_loop.clone();
+
+        MessagingService::new(
+            dispatcher.local_node.clone(),
+            config.codec,
+            config.messaging,
+            config.streams,
+            Arc::new(RwLock::new(dispatcher)),
+            event_loop,
+        )
+    }
+
+    /**Address of the listener, as a member*/
+    fn member(listener: &TcpListener) -> NodeMeta {
+        NodeMeta {
+            id: Uuid::new_v4(),
+            addr: Address {
+                ip: Ipv4Addr::LOCALHOST,
+                port: listener.local_addr().unwrap().port(),
+            },
+        }
+    }
+
+    /**Reads the first message sent to the listener*/
+    fn first_message(listener: TcpListener) -> Receiver<Message> {
+        let (s, r) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut frame = Vec::new();
+            stream.read_to_end(&mut frame).unwrap();
+            s.send(serialize::from_frame::<Message>(&frame).unwrap().0)
+        });
+        r
+    }
+
+    #[test]
+    fn expired_call_is_skipped() {
+        let (dispatcher, caller) = dispatcher();
+        let (s, r) = crossbeam_channel::unbounded();
+        let _handler = dispatcher.register_handler::<String, String, _>("echo", move |arg| {
+            s.send(arg.clone()).unwrap();
+            Ok(arg)
+        });
+
+        let late = rpc(&caller, "late", Duration::from_millis(200), 100);
+        let on_time = rpc(&caller, "on time", Duration::from_millis(0), 1000);
+        dispatcher.handle_in_message(Arc::new(late));
+        dispatcher.handle_in_message(Arc::new(on_time));
+
+        // calls are handled in order, so the expired one was already skipped
+        assert_eq!(r.recv_timeout(Duration::from_secs(1)).unwrap(), "on time");
+        assert!(r.try_recv().is_err());
+    }
+
+    #[test]
+    fn call_in_handler_continues_the_trace() {
+        let (dispatcher, caller) = dispatcher();
+        let service = Arc::new(service(dispatcher));
+
+        let callee = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let target = member(&callee);
+        let sent = first_message(callee);
+
+        let service_ = service.clone();
+        let _handler = service.register_handler("echo", move |arg: String| {
+            let mut options = service_.default_send_options();
+            options
+                .headers
+                .insert(String::from("content-type"), String::from("text"));
+            // nobody answers, only the sent message matters
+            let _ = service_.call_with::<String, String>(
+                &target,
+                "inner",
+                &arg,
+                Duration::from_millis(50),
+                &options,
+            );
+            Ok(arg)
+        });
+
+        let parent = TraceContext::new_root();
+        let mut msg = rpc(&caller, "traced", Duration::from_millis(0), 1000);
+        msg.headers
+            .insert(TRACEPARENT.to_string(), parent.to_traceparent());
+        service
+            .message_dispatcher
+            .read()
+            .unwrap()
+            .handle_in_message(Arc::new(msg));
+
+        let sent = sent.recv_timeout(Duration::from_secs(2)).unwrap();
+        let context = TraceContext::from_message(&sent).unwrap();
+        assert_eq!(context.trace_id, parent.trace_id);
+        assert_ne!(context.span_id, parent.span_id);
+        assert_eq!(sent.header("content-type"), Some("text"));
+    }
+
+    #[test]
+    fn sends_to_unknown_id_fail() {
+        let (dispatcher, _) = dispatcher();
+        let local_node = dispatcher.local_node.clone();
+        let event_loop = dispatcher.event_loop.clone();
+        let service = Arc::new(RwLock::new(service(dispatcher)));
+
+        let config = HoverConfig::default().unwrap();
+        let membership = Arc::new(RwLock::new(MembershipService::new(
+            local_node,
+            config.discovery,
+            service.clone(),
+            event_loop,
+        )));
+        let service = service.read().unwrap();
+        service.bind_membership_service(&membership);
+
+        let unknown = Uuid::new_v4();
+        let err = service.send_to_id(unknown, vec![1]).unwrap_err();
+        assert!(err.is::<UnknownMember>(), "{}", err);
+
+        let err = service
+            .request_to_id(unknown, vec![1], Duration::from_millis(50))
+            .unwrap_err();
+        assert!(err.is::<UnknownMember>(), "{}", err);
+
+        // the member is resolved again before every attempt
+        let mut options = service.default_send_options();
+        options.retry.max_attempts = 3;
+        options.retry.base_delay = Duration::from_millis(1);
+        let err = service
+            .send_to_id_with(unknown, vec![1], &options)
+            .unwrap_err();
+        assert!(err.is::<UnknownMember>(), "{}", err);
+
+        // a suspected member is not resolved, an alive one is
+        let alive = NodeMeta {
+            id: Uuid::new_v4(),
+            addr: Address {
+                ip: Ipv4Addr::LOCALHOST,
+                port: 7001,
+            },
+        };
+        let suspected = NodeMeta {
+            id: Uuid::new_v4(),
+            addr: Address {
+                ip: Ipv4Addr::LOCALHOST,
+                port: 7002,
+            },
+        };
+        let update = |node: &NodeMeta, status| MembershipUpdate {
+            node: node.clone(),
+            status,
+            incarnation: 0,
+            tags: HashMap::new(),
+            from: unknown,
+        };
+        membership
+            .read()
+            .unwrap()
+            .on_event(Event::MembershipUpdatesIn {
+                updates: vec![
+                    update(&alive, MemberStatus::Alive),
+                    update(&suspected, MemberStatus::Alive),
+                    update(&suspected, MemberStatus::Suspect),
+                ],
+            });
+
+        assert_eq!(service.resolve_member(alive.id).unwrap(), alive);
+        let err = service.send_to_id(suspected.id, vec![1]).unwrap_err();
+        assert!(err.is::<UnknownMember>(), "{}", err);
+    }
+
+    #[test]
+    fn to_millis_saturates() {
+        assert_eq!(to_millis(Duration::from_millis(1500)), 1500);
+        assert_eq!(to_millis(Duration::from_secs(u64::MAX)), u64::MAX);
+    }
 }
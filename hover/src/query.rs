@@ -0,0 +1,276 @@
+extern crate rand;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use self::rand::seq::SliceRandom;
+use crate::common::{Member, MemberStatus, Message, NodeMeta, RpcError};
+use crossbeam_channel::Receiver;
+use uuid::Uuid;
+
+/**Selects the members a query is sent to. Default one selects every alive or suspected member*/
+#[derive(Debug, Clone, Default)]
+pub struct QueryFilter {
+    /**Only these members, if set*/
+    pub node_ids: Option<HashSet<Uuid>>,
+    /**Members having all of these tags*/
+    pub tags: HashMap<String, String>,
+    /**N random members out of the matching ones, if set*/
+    pub random: Option<usize>,
+}
+
+impl QueryFilter {
+    pub(crate) fn select(&self, members: Vec<Member>) -> Vec<NodeMeta> {
+        let mut selected: Vec<NodeMeta> = members
+            .into_iter()
+            .filter(|m| m.status == MemberStatus::Alive || m.status == MemberStatus::Suspect)
+            .filter(|m| match self.node_ids {
+                Some(ref ids) => ids.contains(&m.node.id),
+                None => true,
+            })
+            .filter(|m| self.tags.iter().all(|(k, v)| m.tags.get(k) == Some(v)))
+            .map(|m| m.node)
+            .collect();
+
+        if let Some(n) = self.random {
+            selected.shuffle(&mut rand::thread_rng());
+            selected.truncate(n);
+        }
+
+        selected
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryResponse {
+    pub member: NodeMeta,
+    pub result: Result<Arc<Message>, RpcError>,
+}
+
+/**Collects the responses of a query as they arrive. Iterating stops when every
+queried member answered or the timeout passed*/
+pub struct QueryResponses {
+    receiver: Receiver<QueryResponse>,
+    pending: usize,
+    deadline: Instant,
+}
+
+impl QueryResponses {
+    pub(crate) fn new(
+        receiver: Receiver<QueryResponse>,
+        expected: usize,
+        timeout: Duration,
+    ) -> QueryResponses {
+        QueryResponses {
+            receiver,
+            pending: expected,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    /**Number of members that have not answered yet*/
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /**Collects responses until `enough` of them succeeded, if set, every member answered
+    or the timeout passed. If less than `min` succeeded, the partial result is returned as Err*/
+    pub fn gather(
+        self,
+        min: usize,
+        enough: Option<usize>,
+    ) -> Result<Vec<QueryResponse>, Vec<QueryResponse>> {
+        let mut responses = Vec::new();
+        let mut succeeded = 0;
+
+        for response in self {
+            if response.result.is_ok() {
+                succeeded += 1;
+            }
+            responses.push(response);
+
+            if enough.is_some_and(|n| succeeded >= n) {
+                break; // early completion
+            }
+        }
+
+        if succeeded >= min {
+            Ok(responses)
+        } else {
+            Err(responses)
+        }
+    }
+}
+
+impl Iterator for QueryResponses {
+    type Item = QueryResponse;
+
+    fn next(&mut self) -> Option<QueryResponse> {
+        if self.pending == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        if now >= self.deadline {
+            return None;
+        }
+
+        match self.receiver.recv_timeout(self.deadline - now) {
+            Ok(response) => {
+                self.pending -= 1;
+                Some(response)
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Address, MessageType, Priority};
+    use crossbeam_channel::Sender;
+    use std::collections::BTreeMap;
+    use std::net::Ipv4Addr;
+    use std::time::SystemTime;
+
+    fn member(port: u16, status: MemberStatus, tags: &[(&str, &str)]) -> Member {
+        Member {
+            node: NodeMeta {
+                id: Uuid::new_v4(),
+                addr: Address {
+                    ip: Ipv4Addr::LOCALHOST,
+                    port,
+                },
+            },
+            status,
+            incarnation: 0,
+            tags: tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            joined_at: SystemTime::now(),
+            last_probed_at: None,
+            last_rtt: None,
+        }
+    }
+
+    fn ports(selected: &[NodeMeta]) -> Vec<u16> {
+        let mut ports: Vec<u16> = selected.iter().map(|n| n.addr.port).collect();
+        ports.sort();
+        ports
+    }
+
+    fn response(port: u16, ok: bool) -> QueryResponse {
+        let member = member(port, MemberStatus::Alive, &[]).node;
+        let result = if ok {
+            Ok(Arc::new(Message {
+                cor_id: Uuid::new_v4(),
+                msg_type: MessageType::Response,
+                priority: Priority::User,
+                sender_id: member.id,
+                headers: BTreeMap::new(),
+                timeout_ms: None,
+                received_at: None,
+                payload: Vec::new(),
+                return_address: member.addr.clone(),
+            }))
+        } else {
+            Err(RpcError::Timeout)
+        };
+        QueryResponse { member, result }
+    }
+
+    /**Sender is returned, so members that did not answer yet are waited for*/
+    fn responses(results: &[bool], expected: usize) -> (Sender<QueryResponse>, QueryResponses) {
+        let (s, r) = crossbeam_channel::unbounded();
+        for (i, ok) in results.iter().enumerate() {
+            s.send(response(i as u16, *ok)).unwrap();
+        }
+        (
+            s,
+            QueryResponses::new(r, expected, Duration::from_millis(100)),
+        )
+    }
+
+    #[test]
+    fn default_filter_selects_alive_and_suspected_members() {
+        let members = vec![
+            member(1, MemberStatus::Alive, &[]),
+            member(2, MemberStatus::Suspect, &[]),
+            member(3, MemberStatus::Dead, &[]),
+            member(4, MemberStatus::Left, &[]),
+        ];
+
+        assert_eq!(ports(&QueryFilter::default().select(members)), vec![1, 2]);
+    }
+
+    #[test]
+    fn filter_selects_by_ids_and_tags() {
+        let members = vec![
+            member(1, MemberStatus::Alive, &[("role", "db"), ("dc", "a")]),
+            member(2, MemberStatus::Alive, &[("role", "db"), ("dc", "b")]),
+            member(3, MemberStatus::Alive, &[("role", "web"), ("dc", "a")]),
+        ];
+
+        let mut tags = HashMap::new();
+        tags.insert("role".to_string(), "db".to_string());
+        let by_tags = QueryFilter {
+            tags,
+            ..QueryFilter::default()
+        };
+        assert_eq!(ports(&by_tags.select(members.clone())), vec![1, 2]);
+
+        let by_ids = QueryFilter {
+            node_ids: Some(
+                vec![members[0].node.id, members[2].node.id]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..by_tags
+        };
+        assert_eq!(ports(&by_ids.select(members)), vec![1]);
+    }
+
+    #[test]
+    fn filter_selects_random_subset() {
+        let members: Vec<Member> = (0..10)
+            .map(|port| member(port, MemberStatus::Alive, &[]))
+            .collect();
+        let filter = QueryFilter {
+            random: Some(3),
+            ..QueryFilter::default()
+        };
+
+        let selected = ports(&filter.select(members));
+        assert_eq!(selected.len(), 3);
+        assert!(selected.windows(2).all(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn gather_completes_early_with_enough_successes() {
+        let (_s, responses) = responses(&[true, false, true, true], 4);
+        let gathered = responses.gather(1, Some(2));
+
+        assert_eq!(gathered.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn gather_fails_with_less_than_min_successes() {
+        let (_s, responses) = responses(&[true, false, false], 3);
+        let gathered = responses.gather(2, None);
+
+        assert_eq!(gathered.unwrap_err().len(), 3);
+    }
+
+    #[test]
+    fn gather_stops_at_timeout() {
+        let started = Instant::now();
+        let (_s, responses) = responses(&[true], 3);
+        let gathered = responses.gather(1, None);
+
+        assert_eq!(gathered.unwrap().len(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use uuid::Uuid;
+
+use crate::common::{Address, ReliableAck, ReliableMessage};
+
+/**Receiver did not take the reliable message*/
+#[derive(Debug)]
+pub struct NotDelivered {
+    pub address: Address,
+    pub ack: ReliableAck,
+}
+
+impl fmt::Display for NotDelivered {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Reliable message not delivered to {:?}: {:?}",
+            self.address, self.ack
+        )
+    }
+}
+
+impl Error for NotDelivered {}
+
+/**Sending side of the reliable channel to a peer. Sequence numbers are scoped by
+the channel id, a new id starts the sequence from zero*/
+pub(crate) struct Channel {
+    pub(crate) id: Uuid,
+    pub(crate) next_seq: u64,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            id: Uuid::new_v4(),
+            next_seq: 0,
+        }
+    }
+
+    /**Starts over after the receiver reported a lost message*/
+    pub(crate) fn reset(&mut self) {
+        *self = Channel::new();
+    }
+}
+
+/**Reliable channels of the node. A message is sent only after the previous one
+to the same peer was acknowledged, so peers receive them in order*/
+pub(crate) struct ReliableChannels {
+    outgoing: Mutex<HashMap<Address, Arc<Mutex<Channel>>>>,
+    incoming: Mutex<HashMap<Address, (Uuid, u64)>>,
+}
+
+impl ReliableChannels {
+    pub(crate) fn new() -> ReliableChannels {
+        ReliableChannels {
+            outgoing: Mutex::new(HashMap::new()),
+            incoming: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**Channel to the peer. It has to stay locked until the message is acknowledged*/
+    pub(crate) fn channel(&self, address: &Address) -> Arc<Mutex<Channel>> {
+        self.outgoing
+            .lock()
+            .unwrap()
+            .entry(address.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(Channel::new())))
+            .clone()
+    }
+
+    /**Delivers the message if it is the next one of the channel. A retransmission of
+    a delivered message is acknowledged without delivering it again. A message after a
+    gap is not delivered, so the sender starts a new channel and nothing is skipped.
+    The channel stays locked while delivering, retransmissions may arrive concurrently*/
+    pub(crate) fn accept<F>(&self, from: &Address, msg: &ReliableMessage, deliver: F) -> ReliableAck
+    where
+        F: FnOnce() -> bool,
+    {
+        let mut incoming = self.incoming.lock().unwrap();
+        let expected = match incoming.get(from) {
+            Some((channel, expected)) if *channel == msg.channel => *expected,
+            // new channel of the peer, e.g. after a restart or a lost message
+            _ => 0,
+        };
+
+        if msg.seq < expected {
+            return ReliableAck::Delivered;
+        }
+        if msg.seq > expected {
+            return ReliableAck::OutOfOrder;
+        }
+        if !deliver() {
+            return ReliableAck::Refused;
+        }
+
+        incoming.insert(from.clone(), (msg.channel, msg.seq + 1));
+        ReliableAck::Delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::net::Ipv4Addr;
+
+    fn address() -> Address {
+        Address {
+            ip: Ipv4Addr::LOCALHOST,
+            port: 7000,
+        }
+    }
+
+    fn message(channel: Uuid, seq: u64) -> ReliableMessage {
+        ReliableMessage {
+            channel,
+            seq,
+            payload: vec![seq as u8],
+        }
+    }
+
+    #[test]
+    fn retransmission_is_not_delivered_twice() {
+        let channels = ReliableChannels::new();
+        let channel = Uuid::new_v4();
+        let delivered = RefCell::new(Vec::new());
+        let deliver = |msg: &ReliableMessage| {
+            channels.accept(&address(), msg, || {
+                delivered.borrow_mut().push(msg.seq);
+                true
+            })
+        };
+
+        assert_eq!(deliver(&message(channel, 0)), ReliableAck::Delivered);
+        assert_eq!(deliver(&message(channel, 0)), ReliableAck::Delivered);
+        assert_eq!(deliver(&message(channel, 1)), ReliableAck::Delivered);
+        assert_eq!(deliver(&message(channel, 0)), ReliableAck::Delivered);
+
+        assert_eq!(*delivered.borrow(), vec![0, 1]);
+    }
+
+    #[test]
+    fn gap_is_reported_and_not_delivered() {
+        let channels = ReliableChannels::new();
+        let channel = Uuid::new_v4();
+        let delivered = RefCell::new(Vec::new());
+        let deliver = |msg: &ReliableMessage| {
+            channels.accept(&address(), msg, || {
+                delivered.borrow_mut().push((msg.channel, msg.seq));
+                true
+            })
+        };
+
+        assert_eq!(deliver(&message(channel, 0)), ReliableAck::Delivered);
+        assert_eq!(deliver(&message(channel, 2)), ReliableAck::OutOfOrder);
+        assert_eq!(deliver(&message(channel, 1)), ReliableAck::Delivered);
+        assert_eq!(deliver(&message(channel, 2)), ReliableAck::Delivered);
+
+        // unknown channel has to start from zero
+        let restarted = Uuid::new_v4();
+        assert_eq!(deliver(&message(restarted, 1)), ReliableAck::OutOfOrder);
+        assert_eq!(deliver(&message(restarted, 0)), ReliableAck::Delivered);
+
+        assert_eq!(
+            *delivered.borrow(),
+            vec![(channel, 0), (channel, 1), (channel, 2), (restarted, 0)]
+        );
+    }
+
+    #[test]
+    fn refused_message_is_delivered_on_retransmission() {
+        let channels = ReliableChannels::new();
+        let channel = Uuid::new_v4();
+
+        assert_eq!(
+            channels.accept(&address(), &message(channel, 0), || false),
+            ReliableAck::Refused
+        );
+        assert_eq!(
+            channels.accept(&address(), &message(channel, 0), || true),
+            ReliableAck::Delivered
+        );
+        assert_eq!(
+            channels.accept(&address(), &message(channel, 1), || false),
+            ReliableAck::Refused
+        );
+        assert_eq!(
+            channels.accept(&address(), &message(channel, 2), || true),
+            ReliableAck::OutOfOrder
+        );
+    }
+}
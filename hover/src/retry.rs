@@ -0,0 +1,353 @@
+extern crate rand;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use self::rand::Rng;
+use crate::common::Address;
+use crate::config::MessagingConfig;
+use crate::events::{Event, EventListener};
+use uuid::Uuid;
+
+/**How failed user messages are retried*/
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /**Total number of attempts, including the first one*/
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /**Fraction of the backoff delay that is randomized*/
+    pub jitter: f64,
+    /**Messages that may have been delivered are retried only if they are idempotent.
+    Messages that were not sent, because the member could not be connected or
+    resolved, are always retried*/
+    pub idempotent_only: bool,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &MessagingConfig) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: config.retry_max_attempts.max(1),
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+            jitter: config.retry_jitter.clamp(0_f64, 1_f64),
+            idempotent_only: config.retry_idempotent_only,
+        }
+    }
+
+    /**Single attempt*/
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+            jitter: 0_f64,
+            idempotent_only: true,
+        }
+    }
+
+    /**Exponential backoff before the next attempt. Attempts are counted from 1*/
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = 2_u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = (self.base_delay * exp).min(self.max_delay);
+
+        let factor = 1_f64 - self.jitter * rand::thread_rng().gen::<f64>();
+        let millis = delay.as_secs() as f64 * 1000_f64 + delay.subsec_millis() as f64;
+        Duration::from_millis((millis * factor) as u64)
+    }
+
+    pub(crate) fn should_retry(
+        &self,
+        err: &(dyn Error + 'static),
+        idempotent: bool,
+        attempt: u32,
+    ) -> bool {
+        if attempt >= self.max_attempts {
+            return false;
+        }
+
+        err.is::<ConnectError>() || err.is::<UnknownMember>() || idempotent || !self.idempotent_only
+    }
+}
+
+/**Options of a single user send*/
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    pub retry: RetryPolicy,
+    /**Message can be safely delivered more than once*/
+    pub idempotent: bool,
+    /**Headers added to the message*/
+    pub headers: BTreeMap<String, String>,
+}
+
+/**Message could not be sent at all*/
+#[derive(Debug)]
+pub struct ConnectError(pub io::Error);
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not connect: {}", self.0)
+    }
+}
+
+impl Error for ConnectError {}
+
+/**Circuit breaker of the member is open, so the message was not sent*/
+#[derive(Debug)]
+pub struct CircuitOpenError {
+    pub address: Address,
+}
+
+impl fmt::Display for CircuitOpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Circuit is open for {:?}", self.address)
+    }
+}
+
+impl Error for CircuitOpenError {}
+
+/**No alive member has the id, so the message was not sent*/
+#[derive(Debug)]
+pub struct UnknownMember {
+    pub id: Uuid,
+}
+
+impl fmt::Display for UnknownMember {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "No alive member with id {}", self.id)
+    }
+}
+
+impl Error for UnknownMember {}
+
+struct Breaker {
+    failures: u32,
+    opened_at: Option<Instant>,
+    // half-open breaker let its trial through and waits for the result
+    trial_in_flight: bool,
+}
+
+/**Per-member circuit breakers. A breaker opens after the configured number of
+consecutive failures and lets a single trial message through once the open period
+passed. Membership changes of the member reset its breaker*/
+pub struct CircuitBreakers {
+    failure_threshold: u32,
+    open_duration: Duration,
+    breakers: Mutex<HashMap<Address, Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub(crate) fn new(config: &MessagingConfig) -> CircuitBreakers {
+        CircuitBreakers {
+            failure_threshold: config.breaker_failure_threshold.max(1),
+            open_duration: Duration::from_millis(config.breaker_open_ms),
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_open(&self, address: &Address) -> bool {
+        match self.breakers.lock().unwrap().get(address) {
+            Some(Breaker {
+                opened_at: Some(opened_at),
+                ..
+            }) => opened_at.elapsed() < self.open_duration,
+            _ => false,
+        }
+    }
+
+    /**Returns false if the breaker is open. After the open period exactly one trial
+    is allowed, further callers are refused until its result is recorded*/
+    pub(crate) fn allow(&self, address: &Address) -> bool {
+        let mut breakers = self.breakers.lock().unwrap();
+        match breakers.get_mut(address) {
+            Some(breaker) if breaker.trial_in_flight => false,
+            Some(breaker) => match breaker.opened_at {
+                Some(opened_at) if opened_at.elapsed() < self.open_duration => false,
+                Some(_) => {
+                    // half-open
+                    breaker.trial_in_flight = true;
+                    true
+                }
+                None => true,
+            },
+            None => true,
+        }
+    }
+
+    pub(crate) fn record_success(&self, address: &Address) {
+        self.breakers.lock().unwrap().remove(address);
+    }
+
+    pub(crate) fn record_failure(&self, address: &Address) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(address.clone()).or_insert(Breaker {
+            failures: 0,
+            opened_at: None,
+            trial_in_flight: false,
+        });
+
+        breaker.failures += 1;
+        if breaker.trial_in_flight {
+            // failed trial opens it again right away
+            println!(
+                "[CircuitBreakers]: Trial failed, opened again for {:?}",
+                address
+            );
+            breaker.trial_in_flight = false;
+            breaker.opened_at = Some(Instant::now());
+        } else if breaker.failures >= self.failure_threshold && breaker.opened_at.is_none() {
+            println!("[CircuitBreakers]: Opened for {:?}", address);
+            breaker.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+impl EventListener for CircuitBreakers {
+    fn on_event(&self, event: Event) {
+        match event {
+            Event::MemberAdded { member }
+            | Event::MemberUpdated { member }
+            | Event::MemberLeft { member } => self.record_success(&member.node.addr),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter,
+            idempotent_only: true,
+        }
+    }
+
+    fn breakers(open_duration: Duration) -> CircuitBreakers {
+        CircuitBreakers {
+            failure_threshold: 2,
+            open_duration,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn address() -> Address {
+        Address {
+            ip: Ipv4Addr::LOCALHOST,
+            port: 7000,
+        }
+    }
+
+    fn connect_error() -> Box<dyn Error> {
+        Box::new(ConnectError(io::Error::from(
+            io::ErrorKind::ConnectionRefused,
+        )))
+    }
+
+    fn send_error() -> Box<dyn Error> {
+        Box::new(io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let policy = policy(0_f64);
+        let delays: Vec<u64> = (1..6)
+            .map(|attempt| policy.backoff(attempt).as_millis() as u64)
+            .collect();
+
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn backoff_jitter_only_shortens_delay() {
+        let policy = policy(0.5);
+        for _ in 0..100 {
+            let delay = policy.backoff(2);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn connect_errors_are_always_retried() {
+        let policy = policy(0_f64);
+
+        assert!(policy.should_retry(connect_error().as_ref(), false, 1));
+        assert!(!policy.should_retry(connect_error().as_ref(), false, 3));
+
+        let unknown: Box<dyn Error> = Box::new(UnknownMember { id: Uuid::new_v4() });
+        assert!(policy.should_retry(unknown.as_ref(), false, 1));
+    }
+
+    #[test]
+    fn possibly_delivered_messages_are_retried_if_idempotent() {
+        let policy = policy(0_f64);
+        assert!(!policy.should_retry(send_error().as_ref(), false, 1));
+        assert!(policy.should_retry(send_error().as_ref(), true, 1));
+
+        let any = RetryPolicy {
+            idempotent_only: false,
+            ..policy
+        };
+        assert!(any.should_retry(send_error().as_ref(), false, 1));
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold() {
+        let breakers = breakers(Duration::from_secs(60));
+        let address = address();
+
+        breakers.record_failure(&address);
+        assert!(breakers.allow(&address));
+        breakers.record_failure(&address);
+
+        assert!(breakers.is_open(&address));
+        assert!(!breakers.allow(&address));
+    }
+
+    #[test]
+    fn half_open_breaker_lets_one_trial_through() {
+        let breakers = breakers(Duration::from_millis(20));
+        let address = address();
+        breakers.record_failure(&address);
+        breakers.record_failure(&address);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breakers.allow(&address));
+        // trial still in flight
+        assert!(!breakers.allow(&address));
+
+        // failed trial opens it again right away
+        breakers.record_failure(&address);
+        assert!(!breakers.allow(&address));
+
+        std::thread::sleep(Duration::from_millis(30));
+        let breakers = Arc::new(breakers);
+        let callers: Vec<_> = (0..4)
+            .map(|_| {
+                let breakers_ = breakers.clone();
+                let address_ = address.clone();
+                std::thread::spawn(move || breakers_.allow(&address_))
+            })
+            .collect();
+        let allowed = callers
+            .into_iter()
+            .map(|caller| caller.join().unwrap())
+            .filter(|allowed| *allowed)
+            .count();
+        assert_eq!(allowed, 1);
+        breakers.record_success(&address);
+        breakers.record_failure(&address);
+        assert!(breakers.allow(&address));
+    }
+}
@@ -1,26 +1,287 @@
 extern crate bincode;
+extern crate rmp_serde;
 extern crate serde;
+extern crate serde_cbor;
 
 use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::fmt::{Display, Formatter};
 
-pub fn to_bytes<T: ?Sized>(val: &T) -> Result<Vec<u8>, Box<Error>>
+/**Serialization format. Applications may use any implementation for their payloads*/
+pub trait Codec {
+    /**Identifies the codec in the frames it encoded*/
+    fn id(&self) -> u8;
+
+    fn to_bytes<T>(&self, val: &T) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        T: serde::Serialize + ?Sized;
+
+    #[allow(clippy::wrong_self_convention)]
+    fn from_bytes<'a, T>(&self, bytes: &'a [u8]) -> Result<T, Box<dyn Error>>
+    where
+        T: serde::de::Deserialize<'a>;
+}
+
+pub struct Bincode;
+
+pub struct MessagePack;
+
+pub struct Cbor;
+
+impl Codec for Bincode {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn to_bytes<T>(&self, val: &T) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        match bincode::serialize(val) {
+            Ok(vector) => Ok(vector),
+            Err(ex) => Err(Box::new(ex)),
+        }
+    }
+
+    fn from_bytes<'a, T>(&self, bytes: &'a [u8]) -> Result<T, Box<dyn Error>>
+    where
+        T: serde::de::Deserialize<'a>,
+    {
+        match bincode::deserialize(bytes) {
+            Ok(obj) => Ok(obj),
+            Err(ex) => Err(Box::new(ex)),
+        }
+    }
+}
+
+impl Codec for MessagePack {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn to_bytes<T>(&self, val: &T) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        match rmp_serde::to_vec(val) {
+            Ok(vector) => Ok(vector),
+            Err(ex) => Err(Box::new(ex)),
+        }
+    }
+
+    fn from_bytes<'a, T>(&self, bytes: &'a [u8]) -> Result<T, Box<dyn Error>>
+    where
+        T: serde::de::Deserialize<'a>,
+    {
+        match rmp_serde::from_slice(bytes) {
+            Ok(obj) => Ok(obj),
+            Err(ex) => Err(Box::new(ex)),
+        }
+    }
+}
+
+impl Codec for Cbor {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn to_bytes<T>(&self, val: &T) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        let mut vector = Vec::new();
+        let mut serializer =
+            serde_cbor::Serializer::new(serde_cbor::ser::IoWrite::new(&mut vector));
+        match val.serialize(&mut serializer) {
+            Ok(_) => Ok(vector),
+            Err(ex) => Err(Box::new(ex)),
+        }
+    }
+
+    fn from_bytes<'a, T>(&self, bytes: &'a [u8]) -> Result<T, Box<dyn Error>>
+    where
+        T: serde::de::Deserialize<'a>,
+    {
+        match serde_cbor::from_slice(bytes) {
+            Ok(obj) => Ok(obj),
+            Err(ex) => Err(Box::new(ex)),
+        }
+    }
+}
+
+/**Codec selected at runtime, e.g. from the config*/
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecKind {
+    Bincode,
+    MessagePack,
+    Cbor,
+}
+
+impl CodecKind {
+    pub fn from_id(id: u8) -> Option<CodecKind> {
+        match id {
+            0 => Some(CodecKind::Bincode),
+            1 => Some(CodecKind::MessagePack),
+            2 => Some(CodecKind::Cbor),
+            _ => None,
+        }
+    }
+}
+
+impl Codec for CodecKind {
+    fn id(&self) -> u8 {
+        match self {
+            CodecKind::Bincode => Bincode.id(),
+            CodecKind::MessagePack => MessagePack.id(),
+            CodecKind::Cbor => Cbor.id(),
+        }
+    }
+
+    fn to_bytes<T>(&self, val: &T) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        match self {
+            CodecKind::Bincode => Bincode.to_bytes(val),
+            CodecKind::MessagePack => MessagePack.to_bytes(val),
+            CodecKind::Cbor => Cbor.to_bytes(val),
+        }
+    }
+
+    fn from_bytes<'a, T>(&self, bytes: &'a [u8]) -> Result<T, Box<dyn Error>>
+    where
+        T: serde::de::Deserialize<'a>,
+    {
+        match self {
+            CodecKind::Bincode => Bincode.from_bytes(bytes),
+            CodecKind::MessagePack => MessagePack.from_bytes(bytes),
+            CodecKind::Cbor => Cbor.from_bytes(bytes),
+        }
+    }
+}
+
+/**Payloads of the protocol are always bincode*/
+pub fn to_bytes<T>(val: &T) -> Result<Vec<u8>, Box<dyn Error>>
 where
-    T: serde::Serialize,
+    T: serde::Serialize + ?Sized,
 {
-    match bincode::serialize(val) {
-        Ok(vector) => Ok(vector),
-        Err(ex) => Err(Box::new(ex)),
-    }
+    Bincode.to_bytes(val)
 }
 
-pub fn from_bytes<'a, T: ?Sized>(bytes: &'a [u8]) -> Result<T, Box<Error>>
+pub fn from_bytes<'a, T>(bytes: &'a [u8]) -> Result<T, Box<dyn Error>>
 where
     T: serde::de::Deserialize<'a>,
 {
-    match bincode::deserialize(bytes) {
-        Ok(obj) => Ok(obj),
-        Err(ex) => Err(Box::new(ex)),
+    Bincode.from_bytes(bytes)
+}
+
+/**Encodes an envelope prefixed with the id of the codec*/
+pub fn to_frame<T>(codec: CodecKind, val: &T) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    T: serde::Serialize + ?Sized,
+{
+    let mut frame = vec![codec.id()];
+    frame.extend(codec.to_bytes(val)?);
+    Ok(frame)
+}
+
+/**Decodes the frame with the codec it names. The codec is returned as well,
+so frames of nodes configured with another codec can be reported*/
+pub fn from_frame<'a, T>(frame: &'a [u8]) -> Result<(T, CodecKind), Box<dyn Error>>
+where
+    T: serde::de::Deserialize<'a>,
+{
+    let codec = match frame.first() {
+        Some(id) => match CodecKind::from_id(*id) {
+            Some(codec) => codec,
+            None => return Err(Box::from(format!("Unknown codec id {}", id))),
+        },
+        None => return Err(Box::from("Empty frame")),
+    };
+
+    Ok((codec.from_bytes(&frame[1..])?, codec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Address, Message, MessageType, Priority, RpcError, RpcResponse};
+    use std::collections::BTreeMap;
+    use std::net::Ipv4Addr;
+    use uuid::Uuid;
+
+    const CODECS: [CodecKind; 3] = [CodecKind::Bincode, CodecKind::MessagePack, CodecKind::Cbor];
+
+    fn message() -> Message {
+        let mut headers = BTreeMap::new();
+        headers.insert(String::from("content-type"), String::from("json"));
+
+        Message {
+            cor_id: Uuid::new_v4(),
+            msg_type: MessageType::Request,
+            priority: Priority::User,
+            sender_id: Uuid::new_v4(),
+            headers,
+            timeout_ms: Some(500),
+            received_at: None,
+            payload: vec![0, 1, 2, 255],
+            return_address: Address {
+                ip: Ipv4Addr::new(10, 0, 0, 1),
+                port: 6202,
+            },
+        }
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        let msg = message();
+        let response = RpcResponse {
+            result: Err(RpcError::HandlerFailed(String::from("failed"))),
+        };
+
+        for codec in CODECS.iter() {
+            let bytes = codec.to_bytes(&msg).unwrap();
+            assert_eq!(
+                codec.from_bytes::<Message>(&bytes).unwrap(),
+                msg,
+                "{:?}",
+                codec
+            );
+
+            let bytes = codec.to_bytes(&response).unwrap();
+            assert_eq!(
+                codec.from_bytes::<RpcResponse>(&bytes).unwrap(),
+                response,
+                "{:?}",
+                codec
+            );
+        }
+    }
+
+    #[test]
+    fn frame_names_its_codec() {
+        let msg = message();
+
+        for codec in CODECS.iter() {
+            let frame = to_frame(*codec, &msg).unwrap();
+            assert_eq!(CodecKind::from_id(frame[0]), Some(*codec));
+
+            let (decoded, decoded_codec) = from_frame::<Message>(&frame).unwrap();
+            assert_eq!(decoded, msg);
+            assert_eq!(decoded_codec, *codec);
+        }
+    }
+
+    #[test]
+    fn invalid_frames_are_rejected() {
+        assert!(from_frame::<Message>(&[]).is_err());
+
+        let mut frame = to_frame(CodecKind::Bincode, &message()).unwrap();
+        frame[0] = 9;
+        assert!(from_frame::<Message>(&frame).is_err());
+
+        // truncated body
+        let frame = to_frame(CodecKind::Cbor, &message()).unwrap();
+        assert!(from_frame::<Message>(&frame[..frame.len() / 2]).is_err());
     }
 }
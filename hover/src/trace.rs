@@ -0,0 +1,200 @@
+extern crate rand;
+
+use std::cell::RefCell;
+use std::fmt;
+
+use self::rand::Rng;
+use crate::common::Message;
+
+/**Header carrying the trace context of a message*/
+pub const TRACEPARENT: &str = "traceparent";
+
+const VERSION: u8 = 0;
+const SAMPLED: u8 = 1;
+
+thread_local! {
+    static CURRENT: RefCell<Option<TraceContext>> = const { RefCell::new(None) };
+}
+
+/**W3C trace context. Span id is the id of the span that sent the message*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /**Starts a new trace*/
+    pub fn new_root() -> TraceContext {
+        let mut rng = rand::thread_rng();
+        let trace_id = (u128::from(rng.gen::<u64>()) << 64) | u128::from(rng.gen::<u64>());
+
+        TraceContext {
+            trace_id: trace_id.max(1),
+            span_id: new_span_id(),
+            flags: SAMPLED,
+        }
+    }
+
+    /**New span of the same trace*/
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id,
+            span_id: new_span_id(),
+            flags: self.flags,
+        }
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!(
+            "{:02x}-{:032x}-{:016x}-{:02x}",
+            VERSION, self.trace_id, self.span_id, self.flags
+        )
+    }
+
+    /**Parses a traceparent value. Versions after 00 may append fields, which are
+    ignored. Fields are lowercase hex*/
+    pub fn from_traceparent(value: &str) -> Option<TraceContext> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" {
+            return None;
+        }
+        if parts[0] == "00" && parts.len() != 4 {
+            return None;
+        }
+        if parts[1].len() != 32 || parts[2].len() != 16 || parts[3].len() != 2 {
+            return None;
+        }
+        // from_str_radix also takes uppercase digits and a leading sign
+        if !parts[..4].iter().all(|part| part.chars().all(is_lower_hex)) {
+            return None;
+        }
+
+        let trace_id = u128::from_str_radix(parts[1], 16).ok()?;
+        let span_id = u64::from_str_radix(parts[2], 16).ok()?;
+        let flags = u8::from_str_radix(parts[3], 16).ok()?;
+
+        // all zero ids are invalid
+        if trace_id == 0 || span_id == 0 {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            flags,
+        })
+    }
+
+    pub fn from_message(msg: &Message) -> Option<TraceContext> {
+        msg.header(TRACEPARENT)
+            .and_then(TraceContext::from_traceparent)
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_traceparent())
+    }
+}
+
+/**Context of the message handled on this thread. Messages sent by a handler
+continue its trace*/
+pub fn current() -> Option<TraceContext> {
+    CURRENT.with(|current| *current.borrow())
+}
+
+/**Makes the context current on this thread until the guard is dropped*/
+pub fn enter(context: Option<TraceContext>) -> ContextGuard {
+    let previous = CURRENT.with(|current| current.replace(context));
+    ContextGuard { previous }
+}
+
+/**Restores the previous context when dropped*/
+pub struct ContextGuard {
+    previous: Option<TraceContext>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| current.replace(previous));
+    }
+}
+
+/**Context of a new message: a child of the current one or a new trace*/
+pub(crate) fn next_context() -> TraceContext {
+    match current() {
+        Some(context) => context.child(),
+        None => TraceContext::new_root(),
+    }
+}
+
+fn is_lower_hex(c: char) -> bool {
+    c.is_ascii_digit() || ('a'..='f').contains(&c)
+}
+
+fn new_span_id() -> u64 {
+    rand::thread_rng().gen::<u64>().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn traceparent_round_trip() {
+        let context = TraceContext::from_traceparent(VALID).unwrap();
+        assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(context.span_id, 0x00f067aa0ba902b7);
+        assert!(context.is_sampled());
+        assert_eq!(context.to_traceparent(), VALID);
+    }
+
+    #[test]
+    fn version_00_has_exactly_four_fields() {
+        assert_eq!(
+            TraceContext::from_traceparent(&format!("{}-extra", VALID)),
+            None
+        );
+
+        let future = format!("01{}-extra", &VALID[2..]);
+        assert!(TraceContext::from_traceparent(&future).is_some());
+    }
+
+    #[test]
+    fn invalid_traceparents_are_rejected() {
+        let invalid = [
+            "",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-+bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-+0f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-+1",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        ];
+
+        for value in invalid.iter() {
+            assert_eq!(TraceContext::from_traceparent(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn messages_continue_the_current_trace() {
+        let parent = TraceContext::new_root();
+        let _guard = enter(Some(parent));
+
+        let next = next_context();
+        assert_eq!(next.trace_id, parent.trace_id);
+        assert_ne!(next.span_id, parent.span_id);
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+use uuid::Uuid;
+
+use crate::common::{Address, StreamAck, StreamChunk};
+use crate::config::StreamConfig;
+use crate::events::Subscription;
+
+/**Failure of a streamed transfer. Offset is the next one the receiver expects,
+so the transfer can be resumed from it*/
+#[derive(Debug)]
+pub struct StreamError {
+    pub stream_id: Uuid,
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Stream {} failed at offset {}: {}",
+            self.stream_id, self.offset, self.reason
+        )
+    }
+}
+
+impl Error for StreamError {}
+
+type States = Mutex<HashMap<Uuid, StreamState>>;
+
+enum StreamState {
+    Open {
+        sender: Sender<(Vec<u8>, bool)>,
+        next_offset: u64,
+    },
+    /**Last chunk was taken. Its retransmissions are acked*/
+    Completed { end_offset: u64, at: Instant },
+    /**Handler gave up waiting for the next chunk. The stream can be resumed from the offset*/
+    Interrupted { offset: u64, at: Instant },
+}
+
+struct StreamHandler(Box<dyn Fn(IncomingStream) + Send + Sync>);
+
+/**Receiving side of a stream. Chunks are buffered up to the configured window,
+the sender waits while the window is full*/
+pub struct IncomingStream {
+    id: Uuid,
+    sender: Address,
+    start_offset: u64,
+    received: u64,
+    receiver: Receiver<(Vec<u8>, bool)>,
+    buffer: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    timed_out: bool,
+    idle_timeout: Duration,
+    states: Weak<States>,
+}
+
+impl IncomingStream {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /**Address of the sending node*/
+    pub fn sender(&self) -> &Address {
+        &self.sender
+    }
+
+    /**Offset of the first byte. It is not zero if the stream resumes one that was
+    interrupted after the idle timeout*/
+    pub fn start_offset(&self) -> u64 {
+        self.start_offset
+    }
+}
+
+impl Read for IncomingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buffer.len() {
+                let n = buf.len().min(self.buffer.len() - self.pos);
+                buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+
+            if self.eof {
+                return Ok(0);
+            }
+
+            match self.receiver.recv_timeout(self.idle_timeout) {
+                Ok((data, last)) => {
+                    self.received += data.len() as u64;
+                    self.buffer = data;
+                    self.pos = 0;
+                    self.eof = last;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.timed_out = true;
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "No chunk received within the idle timeout",
+                    ));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Stream was closed before its last chunk",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for IncomingStream {
+    fn drop(&mut self) {
+        let states = match self.states.upgrade() {
+            Some(states) => states,
+            None => return,
+        };
+        let mut states = states.lock().unwrap();
+
+        if let Some(StreamState::Open { .. }) = states.get(&self.id) {
+            if self.timed_out {
+                // every received chunk was read before the timeout
+                let offset = self.start_offset + self.received;
+                states.insert(
+                    self.id,
+                    StreamState::Interrupted {
+                        offset,
+                        at: Instant::now(),
+                    },
+                );
+            } else {
+                // further chunks of the stream are refused
+                states.remove(&self.id);
+            }
+        }
+    }
+}
+
+/**Incoming streams of the node. Each accepted stream is handed over to the
+stream handler on its own thread, up to the configured number at a time*/
+pub(crate) struct Streams {
+    window: usize,
+    idle_timeout: Duration,
+    max_concurrent: usize,
+    active: Arc<AtomicUsize>,
+    states: Arc<States>,
+    handler: Arc<RwLock<Option<Arc<StreamHandler>>>>,
+}
+
+impl Streams {
+    pub(crate) fn new(config: &StreamConfig) -> Streams {
+        Streams {
+            window: config.window.max(1),
+            idle_timeout: Duration::from_millis(config.idle_timeout_ms),
+            max_concurrent: config.max_concurrent.max(1),
+            active: Arc::new(AtomicUsize::new(0)),
+            states: Arc::new(Mutex::new(HashMap::new())),
+            handler: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub(crate) fn set_handler<F>(&self, f: F) -> Subscription
+    where
+        F: Fn(IncomingStream) + 'static + Send + Sync,
+    {
+        let handler = Arc::new(StreamHandler(Box::new(f)));
+        if let Some(_) = self.handler.write().unwrap().replace(handler.clone()) {
+            println!("[MessageDispatcher]: overrides a stream handler!");
+        }
+
+        let weak_handler = Arc::downgrade(&self.handler);
+        Subscription::new(move || {
+            if let Some(current) = weak_handler.upgrade() {
+                let mut current = current.write().unwrap();
+                let registered = match *current {
+                    Some(ref h) => Arc::ptr_eq(h, &handler),
+                    None => false,
+                };
+                if registered {
+                    current.take();
+                }
+            }
+        })
+    }
+
+    /**Takes the chunk if it is the next one of the stream. The first chunk of an
+    unknown stream opens it, a chunk at the offset an interrupted stream stopped at
+    resumes it. Completed and interrupted streams are remembered for the idle timeout.
+    Never blocks*/
+    pub(crate) fn accept(&self, sender: &Address, chunk: StreamChunk) -> StreamAck {
+        let mut states = self.states.lock().unwrap();
+        let idle_timeout = self.idle_timeout;
+        states.retain(|_, state| match state {
+            StreamState::Completed { at, .. } | StreamState::Interrupted { at, .. } => {
+                at.elapsed() < idle_timeout
+            }
+            StreamState::Open { .. } => true,
+        });
+
+        let start_offset = match states.get(&chunk.stream_id) {
+            None if chunk.offset == 0 => Some(0),
+            None => return closed(0),
+            Some(StreamState::Completed { end_offset, .. }) => {
+                // ack of the last chunk was lost
+                return StreamAck {
+                    offset: *end_offset,
+                    busy: false,
+                    closed: false,
+                };
+            }
+            Some(StreamState::Interrupted { offset, .. }) if chunk.offset == *offset => {
+                Some(*offset)
+            }
+            Some(StreamState::Interrupted { offset, .. }) => return closed(*offset),
+            Some(StreamState::Open { .. }) => None,
+        };
+
+        if let Some(start_offset) = start_offset {
+            if self.active.load(Ordering::Relaxed) >= self.max_concurrent {
+                // sender waits like for a full window
+                return StreamAck {
+                    offset: chunk.offset,
+                    busy: true,
+                    closed: false,
+                };
+            }
+            match self.open(sender, chunk.stream_id, start_offset) {
+                Some(state) => {
+                    states.insert(chunk.stream_id, state);
+                }
+                None => return closed(chunk.offset),
+            }
+        }
+
+        let (sender, next_offset) = match states.get_mut(&chunk.stream_id) {
+            Some(StreamState::Open {
+                sender,
+                next_offset,
+            }) => (sender, next_offset),
+            _ => return closed(0),
+        };
+
+        if chunk.offset != *next_offset {
+            // duplicate or out of order. Sender continues from the expected offset
+            return StreamAck {
+                offset: *next_offset,
+                busy: false,
+                closed: false,
+            };
+        }
+
+        let end_offset = chunk.offset + chunk.data.len() as u64;
+        match sender.try_send((chunk.data, chunk.last)) {
+            Ok(_) => {
+                *next_offset = end_offset;
+                if chunk.last {
+                    states.insert(
+                        chunk.stream_id,
+                        StreamState::Completed {
+                            end_offset,
+                            at: Instant::now(),
+                        },
+                    );
+                }
+                StreamAck {
+                    offset: end_offset,
+                    busy: false,
+                    closed: false,
+                }
+            }
+            Err(TrySendError::Full(_)) => StreamAck {
+                offset: chunk.offset,
+                busy: true,
+                closed: false,
+            },
+            Err(TrySendError::Disconnected(_)) => {
+                states.remove(&chunk.stream_id);
+                closed(chunk.offset)
+            }
+        }
+    }
+
+    fn open(&self, sender: &Address, id: Uuid, start_offset: u64) -> Option<StreamState> {
+        let handler = match *self.handler.read().unwrap() {
+            Some(ref handler) => handler.clone(),
+            None => {
+                println!("[MessageDispatcher]: No stream handler, refusing a stream");
+                return None;
+            }
+        };
+
+        let (s, r) = crossbeam_channel::bounded(self.window);
+        let stream = IncomingStream {
+            id,
+            sender: sender.clone(),
+            start_offset,
+            received: 0,
+            receiver: r,
+            buffer: Vec::new(),
+            pos: 0,
+            eof: false,
+            timed_out: false,
+            idle_timeout: self.idle_timeout,
+            states: Arc::downgrade(&self.states),
+        };
+
+        let active = self.active.clone();
+        active.fetch_add(1, Ordering::Relaxed);
+        std::thread::spawn(move || {
+            (handler.0)(stream);
+            active.fetch_sub(1, Ordering::Relaxed);
+        });
+
+        Some(StreamState::Open {
+            sender: s,
+            next_offset: start_offset,
+        })
+    }
+}
+
+fn closed(offset: u64) -> StreamAck {
+    StreamAck {
+        offset,
+        busy: false,
+        closed: true,
+    }
+}
+
+/**Reads up to the chunk size. A shorter chunk means the reader is exhausted*/
+pub(crate) fn read_chunk<R: Read>(reader: &mut R, chunk_size: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(chunk_size);
+    reader
+        .by_ref()
+        .take(chunk_size as u64)
+        .read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn streams(window: usize, idle_timeout_ms: u64, max_concurrent: usize) -> Streams {
+        Streams::new(&StreamConfig {
+            chunk_size: 4,
+            window,
+            ack_timeout_ms: 1000,
+            idle_timeout_ms,
+            max_concurrent,
+        })
+    }
+
+    /**Opened streams are handed over through the receiver*/
+    fn handled(streams: &Streams) -> (Subscription, Receiver<IncomingStream>) {
+        let (s, r) = crossbeam_channel::unbounded();
+        let subscription = streams.set_handler(move |stream| s.send(stream).unwrap());
+        (subscription, r)
+    }
+
+    fn sender() -> Address {
+        Address {
+            ip: Ipv4Addr::LOCALHOST,
+            port: 7000,
+        }
+    }
+
+    fn chunk(stream_id: Uuid, offset: u64, data: &[u8], last: bool) -> StreamChunk {
+        StreamChunk {
+            stream_id,
+            offset,
+            data: data.to_vec(),
+            last,
+        }
+    }
+
+    fn next(r: &Receiver<IncomingStream>) -> IncomingStream {
+        r.recv_timeout(Duration::from_secs(1)).unwrap()
+    }
+
+    #[test]
+    fn chunks_are_read_in_order() {
+        let streams = streams(4, 1000, 4);
+        let (_subscription, r) = handled(&streams);
+        let id = Uuid::new_v4();
+
+        assert_eq!(
+            streams
+                .accept(&sender(), chunk(id, 0, b"abcd", false))
+                .offset,
+            4
+        );
+        let ack = streams.accept(&sender(), chunk(id, 8, b"ij", true));
+        assert_eq!((ack.offset, ack.closed), (4, false));
+        assert_eq!(
+            streams
+                .accept(&sender(), chunk(id, 4, b"efgh", false))
+                .offset,
+            8
+        );
+        assert_eq!(
+            streams.accept(&sender(), chunk(id, 8, b"ij", true)).offset,
+            10
+        );
+
+        let mut data = Vec::new();
+        next(&r).read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abcdefghij".to_vec());
+    }
+
+    #[test]
+    fn retransmitted_last_chunk_is_acked_once_completed() {
+        let streams = streams(4, 1000, 4);
+        let (_subscription, r) = handled(&streams);
+        let id = Uuid::new_v4();
+
+        assert_eq!(
+            streams.accept(&sender(), chunk(id, 0, b"ab", true)).offset,
+            2
+        );
+        let ack = streams.accept(&sender(), chunk(id, 0, b"ab", true));
+
+        assert_eq!((ack.offset, ack.busy, ack.closed), (2, false, false));
+        next(&r);
+        assert!(r.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn full_window_makes_sender_wait() {
+        let streams = streams(1, 1000, 4);
+        let (_subscription, _r) = handled(&streams);
+        let id = Uuid::new_v4();
+
+        streams.accept(&sender(), chunk(id, 0, b"abcd", false));
+        let ack = streams.accept(&sender(), chunk(id, 4, b"efgh", false));
+
+        assert_eq!((ack.offset, ack.busy), (4, true));
+    }
+
+    #[test]
+    fn streams_over_the_limit_wait() {
+        let streams = streams(4, 1000, 1);
+        let (done_s, done_r) = crossbeam_channel::unbounded();
+        let _subscription = streams.set_handler(move |mut stream| {
+            stream.read_to_end(&mut Vec::new()).unwrap();
+            done_s.send(()).unwrap();
+        });
+        let id = Uuid::new_v4();
+
+        streams.accept(&sender(), chunk(id, 0, b"abcd", false));
+        let ack = streams.accept(&sender(), chunk(Uuid::new_v4(), 0, b"abcd", false));
+        assert!(ack.busy);
+
+        streams.accept(&sender(), chunk(id, 4, b"ef", true));
+        done_r.recv_timeout(Duration::from_secs(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let ack = streams.accept(&sender(), chunk(Uuid::new_v4(), 0, b"abcd", false));
+        assert!(!ack.busy);
+    }
+
+    #[test]
+    fn interrupted_stream_is_resumed_at_its_offset() {
+        let streams = streams(4, 50, 4);
+        let (_subscription, r) = handled(&streams);
+        let id = Uuid::new_v4();
+
+        streams.accept(&sender(), chunk(id, 0, b"abcd", false));
+        let mut stream = next(&r);
+        let mut data = Vec::new();
+        let err = stream.read_to_end(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drop(stream);
+
+        let ack = streams.accept(&sender(), chunk(id, 0, b"abcd", false));
+        assert_eq!((ack.offset, ack.closed), (4, true));
+
+        let ack = streams.accept(&sender(), chunk(id, 4, b"ef", true));
+        assert_eq!((ack.offset, ack.closed), (6, false));
+
+        let mut resumed = next(&r);
+        let mut rest = Vec::new();
+        resumed.read_to_end(&mut rest).unwrap();
+        assert_eq!((resumed.start_offset(), rest), (4, b"ef".to_vec()));
+    }
+
+    #[test]
+    fn dropped_stream_refuses_further_chunks() {
+        let streams = streams(4, 1000, 4);
+        let (_subscription, r) = handled(&streams);
+        let id = Uuid::new_v4();
+
+        streams.accept(&sender(), chunk(id, 0, b"abcd", false));
+        drop(next(&r));
+
+        assert!(
+            streams
+                .accept(&sender(), chunk(id, 4, b"efgh", false))
+                .closed
+        );
+    }
+}
@@ -11,6 +11,7 @@ use std::iter::FromIterator;
 use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
 use futures::future;
 use futures::future::Future;
@@ -24,8 +25,9 @@ use gotham::pipeline::single_middleware;
 use gotham::router::builder::*;
 use gotham::router::Router;
 use gotham::state::{FromState, State};
-use hover::common::{Address, NodeMeta};
-use hover::events::EventListener;
+use hover::common::{Address, Member, MemberStatus, NodeMeta, RpcError};
+use hover::events::Subscription;
+use hover::membership::MembershipListener;
 use hyper::{Body, Response, StatusCode};
 use mime::Mime;
 use serde::{Deserialize, Serialize, Serializer};
@@ -33,6 +35,8 @@ use uuid::Uuid;
 
 pub mod settings;
 
+const RPC_TIMEOUT_MS: u64 = 1000;
+
 #[derive(Clone, StateData)]
 struct HoverState {
     hover: Arc<RwLock<hover::Hover>>,
@@ -50,6 +54,11 @@ struct KvMember {
     id: Uuid,
     http_address: Option<Address>,
     hover_node: NodeMeta,
+    status: MemberStatus,
+    incarnation: u64,
+    tags: HashMap<String, String>,
+    last_probed_at: Option<SystemTime>,
+    last_rtt_ms: Option<u64>,
 }
 
 #[derive(Deserialize)]
@@ -66,17 +75,28 @@ fn get_members(mut state: State) -> (State, Response<Body>) {
         .read()
         .unwrap()
         .get_cluster_service()
-        .map(|ms| ms.read().unwrap().get_members())
+        .map(|ms| ms.read().unwrap().all_members())
         .unwrap();
 
-    members.retain(|nm| kv_nodes.read().unwrap().contains_key(&nm.id));
+    // removed members are kept by hover for a while, they are not part of the cluster
+    members.retain(|m| match m.status {
+        MemberStatus::Alive | MemberStatus::Suspect => {
+            kv_nodes.read().unwrap().contains_key(&m.node.id)
+        }
+        _ => false,
+    });
 
     let kv_members = members
         .into_iter()
-        .map(|nm| KvMember {
-            id: nm.id.clone(),
-            http_address: kv_nodes.read().unwrap().get(&nm.id).map(|g| g.clone()),
-            hover_node: nm,
+        .map(|m| KvMember {
+            id: m.node.id.clone(),
+            http_address: kv_nodes.read().unwrap().get(&m.node.id).map(|g| g.clone()),
+            hover_node: m.node,
+            status: m.status,
+            incarnation: m.incarnation,
+            tags: m.tags,
+            last_probed_at: m.last_probed_at,
+            last_rtt_ms: m.last_rtt.map(|rtt| rtt.as_millis() as u64),
         })
         .collect::<Vec<_>>();
 
@@ -235,7 +255,7 @@ pub fn main() {
     }));
 
     hover.write().unwrap().start();
-    setup_hover(
+    let _subscriptions = setup_hover(
         hover.clone(),
         map.clone(),
         kv_nodes.clone(),
@@ -268,11 +288,11 @@ fn setup_hover(
     map: Arc<RwLock<chashmap::CHashMap<String, String>>>,
     kv_nodes: Arc<RwLock<chashmap::CHashMap<Uuid, hover::common::Address>>>,
     kv_address: Arc<RwLock<hover::common::Address>>,
-) {
+) -> Vec<Subscription> {
     let hover_ = hover.clone();
     let map_ = map.clone();
 
-    hover
+    let broadcasts = hover
         .write()
         .unwrap()
         .add_broadcast_listener(move |msg| {
@@ -289,98 +309,91 @@ fn setup_hover(
         })
         .unwrap();
 
-    let member_added_listener = MapMemberAddedListener {
+    let membership_listener = MapMembershipListener {
         hover: hover.clone(),
         map: map.clone(),
         kv_nodes: kv_nodes.clone(),
         kv_address: kv_address.clone(),
     };
-    hover
+    let membership = hover
         .read()
         .unwrap()
-        .add_event_listener(member_added_listener);
+        .add_membership_listener(membership_listener, true)
+        .unwrap();
 
     let map_ = map.clone();
-    hover.write().unwrap().add_msg_listener(move |msg| {
-        if let hover::common::MessageType::Request = msg.msg_type {
-            let kv_msg: KvMessage = bincode::deserialize(msg.payload.as_slice()).unwrap();
-
-            match kv_msg.msg_type.as_str() {
-                "map" => {
-                    let local_map: HashMap<String, String> =
-                        bincode::deserialize(kv_msg.payload.as_slice()).unwrap();
-
-                    for (key, value) in local_map.into_iter() {
-                        map_.read().unwrap().insert(key, value);
-                    }
-                }
-                "kv_addr" => {
-                    let external_node_addr: UuidAddress =
-                        bincode::deserialize(kv_msg.payload.as_slice()).unwrap();
-
-                    kv_nodes
-                        .read()
-                        .unwrap()
-                        .insert(external_node_addr.id, external_node_addr.address);
-                }
-                _ => {}
+    let map_handler = hover
+        .read()
+        .unwrap()
+        .register_handler("kv.map", move |local_map: HashMap<String, String>| {
+            for (key, value) in local_map.into_iter() {
+                map_.read().unwrap().insert(key, value);
             }
-        }
-    });
+            Ok(())
+        })
+        .unwrap();
+
+    let addr_handler = hover
+        .read()
+        .unwrap()
+        .register_handler("kv.addr", move |external_node_addr: UuidAddress| {
+            kv_nodes
+                .read()
+                .unwrap()
+                .insert(external_node_addr.id, external_node_addr.address);
+            Ok(())
+        })
+        .unwrap();
+
+    vec![broadcasts, membership, map_handler, addr_handler]
 }
 
-struct MapMemberAddedListener {
+struct MapMembershipListener {
     hover: Arc<RwLock<hover::Hover>>,
     map: Arc<RwLock<chashmap::CHashMap<String, String>>>,
     kv_nodes: Arc<RwLock<chashmap::CHashMap<Uuid, hover::common::Address>>>,
     kv_address: Arc<RwLock<hover::common::Address>>,
 }
 
-impl MapMemberAddedListener {
-    fn send_message(&self, msg_type: String, payload: Vec<u8>, node_meta: &NodeMeta) {
-        let msg = KvMessage { msg_type, payload };
-
-        let bytes = bincode::serialize(&msg).unwrap();
-
-        self.hover
+impl MapMembershipListener {
+    fn call<T: Serialize>(&self, method: &str, req: &T, node_meta: &NodeMeta) {
+        let result: Result<(), RpcError> = self
+            .hover
             .read()
             .unwrap()
             .get_messaging_service()
             .unwrap()
             .read()
             .unwrap()
-            .send_to_member(bytes, node_meta);
+            .call(
+                node_meta,
+                method,
+                req,
+                Duration::from_millis(RPC_TIMEOUT_MS),
+            );
+
+        if let Err(err) = result {
+            eprintln!("Error while calling {}: {}", method, err);
+        }
     }
 }
 
-impl EventListener for MapMemberAddedListener {
-    fn on_event(&self, event: hover::events::Event) {
-        match event {
-            hover::events::Event::MemberAdded { node_meta } => {
-                let local_map: HashMap<String, String> =
-                    HashMap::from_iter(self.map.read().unwrap().clone().into_iter());
-                let map_bytes = bincode::serialize(&local_map).unwrap();
-                self.send_message(String::from("map"), map_bytes, &node_meta);
-
-                let uuid_address = UuidAddress {
-                    id: self.hover.read().unwrap().get_node_id().unwrap().clone(),
-                    address: self.kv_address.read().unwrap().clone(),
-                };
-                let addr_bytes = bincode::serialize(&uuid_address).unwrap();
-                self.send_message(String::from("kv_addr"), addr_bytes, &node_meta);
-            }
-            hover::events::Event::MemberLeft { node_meta } => {
-                self.kv_nodes.read().unwrap().remove(&(node_meta.id));
-            }
-            _ => {}
-        }
+impl MembershipListener for MapMembershipListener {
+    fn on_join(&self, member: &Member) {
+        let local_map: HashMap<String, String> =
+            HashMap::from_iter(self.map.read().unwrap().clone().into_iter());
+        self.call("kv.map", &local_map, &member.node);
+
+        let uuid_address = UuidAddress {
+            id: self.hover.read().unwrap().get_node_id().unwrap().clone(),
+            address: self.kv_address.read().unwrap().clone(),
+        };
+        self.call("kv.addr", &uuid_address, &member.node);
     }
-}
 
-#[derive(Deserialize, Serialize)]
-struct KvMessage {
-    msg_type: String,
-    payload: Vec<u8>,
+    fn on_leave(&self, member: &Member) {
+        self.kv_nodes.read().unwrap().remove(&(member.node.id));
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]